@@ -9,3 +9,42 @@ Dirt.rule("sprintf",
           Dirt.Buf(0x10), "%d %s %x", 123, "Foo", 16,
           function (r) return r:str(r:arg(0)) == "123 Foo 10" end)
 
+
+Dirt.rule("strlen", "hello", function (r)
+  return r:return_value() == 5 and #r:writes() == 0
+end)
+
+Dirt.rule("memset", Dirt.Buf(0x10), 0x41, 8, function (r)
+  local writes = r:writes()
+  return r:return_value() == r:arg(0) and
+         #writes == 1 and writes[1].addr == r:arg(0) and
+         writes[1].size == 8 and r:str(r:arg(0)) == "AAAAAAAA"
+end)
+
+Dirt.rule("puts", "hello", function (r)
+  return r:stdout() == "hello\n" and r:stderr() == ""
+end)
+
+Dirt.rule("strdup", "hello", function (r)
+  local allocs = r:allocs()
+  return #allocs == 1 and allocs[1].size == 6 and not allocs[1].freed and
+         allocs[1].addr == r:return_value() and
+         r:str(r:return_value()) == "hello"
+end)
+
+Dirt.rule("strdup", "hello", function (r)
+  -- malloc is called directly, under any of its aliases.
+  local mallocs = 0
+  for _, call in ipairs(r:calls()) do
+    if call.depth == 0 and call.name and call.name:find("malloc") then
+      mallocs = mallocs + 1
+    end
+  end
+  return mallocs == 1
+end, {trace_calls = true})
+
+Dirt.rule("atof", "1.5", function (r) return r:return_double() == 1.5 end)
+Dirt.rule("atof", "-0.25", function (r) return r:return_double() == -0.25 end)
+
+Dirt.rule("sqrt", 2.25, function (r) return r:return_double() == 1.5 end)
+Dirt.rule("sqrt", 16.0, function (r) return r:return_double() == 4.0 end)
@@ -1,5 +1,8 @@
 extern crate elf;
 
+use bin::discovery;
+use emu::loader::elf::load_base;
+use std::collections::HashMap;
 use std::string::FromUtf8Error;
 use std::path::Path;
 
@@ -11,6 +14,8 @@ pub enum ParseError {
 pub struct Symbol {
     pub name: String,
     pub value: u64,
+    /// Size of the symbol, 0 if unknown.
+    pub size: u64,
 }
 
 pub trait BinFile {
@@ -22,39 +27,80 @@ pub trait BinFile {
 
 struct ElfFile {
     elf: elf::File,
+    /// Address the binary is loaded at by the emulator, added to its link
+    /// addresses.
+    base: u64,
 }
 
 impl ElfFile {
     fn from_file(path: &Path) -> Result<ElfFile, ParseError> {
         match elf::File::open_path(path) {
-            Ok(elf) => Ok(ElfFile { elf: elf }),
+            Ok(elf) => {
+                let base = load_base(&elf);
+                Ok(ElfFile {
+                    elf: elf,
+                    base: base,
+                })
+            }
             Err(e) => Err(ParseError::ElfError(e)),
         }
     }
+
+    /// Load address of the link address |value|. Undefined symbols stay 0.
+    fn rebase(&self, value: u64) -> u64 {
+        if value == 0 {
+            return 0;
+        }
+        return value + self.base;
+    }
+
+    /// Returns the .symtab symbols, or nothing if the binary is stripped.
+    fn symbols(&self) -> Vec<elf::types::Symbol> {
+        return match self.elf.get_section(".symtab") {
+            Some(symtab) => self.elf.get_symbols(symtab).unwrap_or(Vec::new()),
+            None => Vec::new(),
+        };
+    }
 }
 
 impl BinFile for ElfFile {
     fn objects(&self) -> Vec<Symbol> {
-        let symtab = self.elf.get_section(".symtab").unwrap();
-        let symbols = self.elf.get_symbols(symtab).unwrap().into_iter();
+        let symbols = self.symbols().into_iter();
         symbols.filter(|s| s.symtype == elf::types::STT_OBJECT)
             .map(|s| {
                 Symbol {
                     name: s.name.clone(),
-                    value: s.value,
+                    value: self.rebase(s.value),
+                    size: s.size,
                 }
             })
             .collect()
     }
 
     fn functions(&self) -> Vec<Symbol> {
-        let symtab = self.elf.get_section(".symtab").unwrap();
-        let symbols = self.elf.get_symbols(symtab).unwrap().into_iter();
+        if self.elf.get_section(".symtab").is_none() {
+            // Stripped binary, find the functions from the code itself.
+            let sizes: HashMap<u64, u64> =
+                discovery::eh_frame_ranges(&self.elf).into_iter().collect();
+            return discovery::discover_functions(&self.elf)
+                .into_iter()
+                .map(|addr| {
+                    Symbol {
+                        name: format!("sub_{:x}", addr + self.base),
+                        value: addr + self.base,
+                        size: sizes.get(&addr).cloned().unwrap_or(0),
+                    }
+                })
+                .collect();
+        }
+
+        let symbols = self.symbols().into_iter();
         symbols.filter(|s| s.symtype == elf::types::STT_FUNC)
             .map(|s| {
                 Symbol {
                     name: s.name.clone(),
-                    value: s.value,
+                    value: self.rebase(s.value),
+                    size: s.size,
                 }
             })
             .collect()
@@ -62,7 +108,7 @@ impl BinFile for ElfFile {
 
     fn read_str(&self, addr: u64) -> Result<String, FromUtf8Error> {
         let section = self.elf.get_section(".rodata").unwrap();
-        let section_va = section.shdr.addr;
+        let section_va = section.shdr.addr + self.base;
         if section_va > addr || addr > section_va + section.shdr.size {
             panic!("{} not in .rodata", addr);
         }
@@ -71,13 +117,13 @@ impl BinFile for ElfFile {
     }
 
     fn get_symbol(&self, name: &str) -> Option<Symbol> {
-        let symtab = self.elf.get_section(".symtab").unwrap();
-        let mut symbols = self.elf.get_symbols(symtab).unwrap().into_iter();
+        let mut symbols = self.symbols().into_iter();
         match symbols.find(|s| s.name == name) {
             Some(s) => {
                 Some(Symbol {
                     name: s.name.clone(),
-                    value: s.value,
+                    value: self.rebase(s.value),
+                    size: s.size,
                 })
             }
             None => None,
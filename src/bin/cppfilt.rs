@@ -5,7 +5,9 @@ pub fn unmangle(sym: &str) -> String {
         .arg("-n")
         .arg(sym)
         .output()
-        .map(|o| String::from_utf8(o.stdout).unwrap())
+        .map(|o| {
+            String::from_utf8(o.stdout).unwrap().trim_right().to_owned()
+        })
         .or::<String>(Ok(String::from(sym)))
         .unwrap()
 }
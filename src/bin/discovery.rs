@@ -0,0 +1,456 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use capstone;
+use elf;
+use std::collections::{BTreeSet, HashSet};
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e {
+        Some(v) => v,
+        None => return None,
+    })
+}
+
+// DWARF pointer encodings used by .eh_frame.
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+/// Find the functions of a binary without relying on its symbols.
+///
+/// Functions are seeded from the entry point, the .eh_frame FDEs and the
+/// .init_array/.fini_array pointers, then every call target found by
+/// recursively disassembling the seeds is added. Returns the functions
+/// addresses, sorted.
+pub fn discover_functions(file: &elf::File) -> Vec<u64> {
+    let mut seeds = BTreeSet::new();
+    if file.ehdr.entry != 0 {
+        seeds.insert(file.ehdr.entry);
+    }
+    for (start, _) in eh_frame_ranges(file) {
+        seeds.insert(start);
+    }
+    for name in &[".init_array", ".fini_array"] {
+        for ptr in section_pointers(file, name) {
+            seeds.insert(ptr);
+        }
+    }
+
+    let functions = match Disassembler::new(file) {
+        Some(disassembler) => disassembler.call_graph(seeds),
+        None => seeds,
+    };
+    return functions.into_iter()
+        .filter(|&addr| addr != 0 && addr != pointer_mask(file))
+        .collect();
+}
+
+fn is_64bits(file: &elf::File) -> bool {
+    return file.ehdr.class == elf::types::ELFCLASS64;
+}
+
+fn is_big_endian(file: &elf::File) -> bool {
+    return file.ehdr.data == elf::types::ELFDATA2MSB;
+}
+
+/// Value of a pointer with all bits set, used as a terminator in arrays.
+fn pointer_mask(file: &elf::File) -> u64 {
+    return if is_64bits(file) {
+        0xffffffffffffffff
+    } else {
+        0xffffffff
+    };
+}
+
+/// Read the pointers stored in a section.
+fn section_pointers(file: &elf::File, name: &str) -> Vec<u64> {
+    let section = match file.get_section(name) {
+        Some(section) => section,
+        None => return Vec::new(),
+    };
+    let mut reader = Reader::new(file, &section.data, section.shdr.addr);
+    let mut pointers = Vec::new();
+    while let Some(ptr) = reader.pointer() {
+        pointers.push(ptr);
+    }
+    return pointers;
+}
+
+/// Parse the .eh_frame section and return the (start, size) range of every
+/// FDE.
+pub fn eh_frame_ranges(file: &elf::File) -> Vec<(u64, u64)> {
+    let section = match file.get_section(".eh_frame") {
+        Some(section) => section,
+        None => return Vec::new(),
+    };
+
+    let mut ranges = Vec::new();
+    let mut reader = Reader::new(file, &section.data, section.shdr.addr);
+    // FDE pointer encoding of each CIE, by offset.
+    let mut cie_encodings = Vec::new();
+    loop {
+        let entry_offset = reader.offset;
+        let length = match reader.u32() {
+            Some(0) | None => break,
+            Some(0xffffffff) => {
+                match reader.u64() {
+                    Some(length) => length as usize,
+                    None => break,
+                }
+            }
+            Some(length) => length as usize,
+        };
+        let entry_start = reader.offset;
+        let next_entry = match entry_start.checked_add(length) {
+            Some(next_entry) if next_entry <= section.data.len() => next_entry,
+            _ => break,
+        };
+
+        let cie_id_offset = reader.offset;
+        match reader.u32() {
+            Some(0) => {
+                let encoding = reader.cie_fde_encoding()
+                    .unwrap_or(DW_EH_PE_ABSPTR);
+                cie_encodings.push((entry_offset, encoding));
+            }
+            Some(cie_ptr) => {
+                // Skip the FDEs of a malformed section, pointing before its
+                // start.
+                let cie_offset = match cie_id_offset.checked_sub(cie_ptr as
+                                                                 usize) {
+                    Some(offset) => offset,
+                    None => {
+                        reader.offset = next_entry;
+                        continue;
+                    }
+                };
+                let encoding = cie_encodings.iter()
+                    .find(|&&(offset, _)| offset == cie_offset)
+                    .map(|&(_, encoding)| encoding)
+                    .unwrap_or(DW_EH_PE_ABSPTR);
+                let start = reader.encoded(encoding);
+                // The range only uses the value format of the encoding.
+                let size = reader.encoded(encoding & 0x0f);
+                if let (Some(start), Some(size)) = (start, size) {
+                    if start != 0 {
+                        ranges.push((start, size));
+                    }
+                }
+            }
+            None => break,
+        }
+
+        reader.offset = next_entry;
+    }
+    return ranges;
+}
+
+/// Reader over a section's data, decoding values in the binary's byte order.
+struct Reader<'a> {
+    data: &'a [u8],
+    /// Virtual address of |data|.
+    addr: u64,
+    offset: usize,
+    is_64bits: bool,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(file: &elf::File, data: &'a [u8], addr: u64) -> Reader<'a> {
+        return Reader {
+            data: data,
+            addr: addr,
+            offset: 0,
+            is_64bits: is_64bits(file),
+            big_endian: is_big_endian(file),
+        };
+    }
+
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.offset + size > self.data.len() {
+            return None;
+        }
+        let bytes = &self.data[self.offset..self.offset + size];
+        self.offset += size;
+        return Some(bytes);
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        return self.take(1).map(|b| b[0]);
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let big_endian = self.big_endian;
+        return self.take(2).map(|b| if big_endian {
+            BigEndian::read_u16(b)
+        } else {
+            LittleEndian::read_u16(b)
+        });
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let big_endian = self.big_endian;
+        return self.take(4).map(|b| if big_endian {
+            BigEndian::read_u32(b)
+        } else {
+            LittleEndian::read_u32(b)
+        });
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let big_endian = self.big_endian;
+        return self.take(8).map(|b| if big_endian {
+            BigEndian::read_u64(b)
+        } else {
+            LittleEndian::read_u64(b)
+        });
+    }
+
+    fn pointer(&mut self) -> Option<u64> {
+        if self.is_64bits {
+            return self.u64();
+        }
+        return self.u32().map(|p| p as u64);
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = try_opt!(self.u8());
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut value: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = try_opt!(self.u8());
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1i64 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let start = self.offset;
+        loop {
+            if try_opt!(self.u8()) == 0 {
+                return Some(&self.data[start..self.offset - 1]);
+            }
+        }
+    }
+
+    /// Parse the rest of a CIE and return the pointer encoding used by its
+    /// FDEs.
+    fn cie_fde_encoding(&mut self) -> Option<u8> {
+        let version = try_opt!(self.u8());
+        let augmentation = try_opt!(self.cstr());
+        if augmentation.starts_with(b"eh") {
+            try_opt!(self.pointer());
+        }
+        // Code and data alignment factors.
+        try_opt!(self.uleb128());
+        try_opt!(self.sleb128());
+        // Return address register.
+        if version == 1 {
+            try_opt!(self.u8());
+        } else {
+            try_opt!(self.uleb128());
+        }
+
+        if !augmentation.starts_with(b"z") {
+            return None;
+        }
+        try_opt!(self.uleb128());
+        for c in &augmentation[1..] {
+            match *c {
+                b'R' => return self.u8(),
+                b'P' => {
+                    let encoding = try_opt!(self.u8());
+                    try_opt!(self.encoded(encoding));
+                }
+                b'L' => {
+                    try_opt!(self.u8());
+                }
+                _ => (),
+            }
+        }
+        return None;
+    }
+
+    /// Read a pointer encoded with a DW_EH_PE_* |encoding|.
+    fn encoded(&mut self, encoding: u8) -> Option<u64> {
+        if encoding == DW_EH_PE_OMIT {
+            return Some(0);
+        }
+
+        let field_addr = self.addr + self.offset as u64;
+        let value = match encoding & 0x0f {
+            DW_EH_PE_ABSPTR => try_opt!(self.pointer()),
+            DW_EH_PE_ULEB128 => try_opt!(self.uleb128()),
+            DW_EH_PE_UDATA2 => try_opt!(self.u16()) as u64,
+            DW_EH_PE_UDATA4 => try_opt!(self.u32()) as u64,
+            DW_EH_PE_UDATA8 => try_opt!(self.u64()),
+            DW_EH_PE_SLEB128 => try_opt!(self.sleb128()) as u64,
+            DW_EH_PE_SDATA2 => try_opt!(self.u16()) as i16 as u64,
+            DW_EH_PE_SDATA4 => try_opt!(self.u32()) as i32 as u64,
+            DW_EH_PE_SDATA8 => try_opt!(self.u64()),
+            _ => return None,
+        };
+
+        return match encoding & 0x70 {
+            0 => Some(value),
+            DW_EH_PE_PCREL => Some(field_addr.wrapping_add(value)),
+            _ => None,
+        };
+    }
+}
+
+/// Recursive disassembler over the executable sections of a binary.
+struct Disassembler<'a> {
+    cs: capstone::Capstone,
+    sections: Vec<&'a elf::Section>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(file: &'a elf::File) -> Option<Disassembler<'a>> {
+        let mode = match file.ehdr.machine {
+            elf::types::EM_386 => capstone::CsMode::MODE_32,
+            elf::types::EM_X86_64 => capstone::CsMode::MODE_64,
+            _ => return None,
+        };
+        let cs = match capstone::Capstone::new(capstone::CsArch::ARCH_X86,
+                                               mode) {
+            Ok(cs) => cs,
+            Err(_) => return None,
+        };
+        let sections = file.sections
+            .iter()
+            .filter(|s| s.shdr.flags.0 & elf::types::SHF_EXECINSTR.0 != 0)
+            .collect();
+        return Some(Disassembler {
+            cs: cs,
+            sections: sections,
+        });
+    }
+
+    /// Returns the code from |addr| up to the end of its section.
+    fn code_at(&self, addr: u64) -> Option<&'a [u8]> {
+        for section in &self.sections {
+            let start = section.shdr.addr;
+            if addr >= start && addr < start + section.data.len() as u64 {
+                return Some(&section.data[(addr - start) as usize..]);
+            }
+        }
+        return None;
+    }
+
+    /// Explore the code reachable from |functions| and return them along
+    /// with every call targets found.
+    fn call_graph(&self, mut functions: BTreeSet<u64>) -> BTreeSet<u64> {
+        let mut pending: Vec<u64> = functions.iter().cloned().collect();
+        let mut visited_blocks = HashSet::new();
+        while let Some(block) = pending.pop() {
+            if !visited_blocks.insert(block) {
+                continue;
+            }
+
+            for (target, is_call) in self.block_targets(block) {
+                if self.code_at(target).is_none() {
+                    continue;
+                }
+                if is_call && functions.insert(target) {
+                    pending.push(target);
+                } else if !is_call {
+                    pending.push(target);
+                }
+            }
+        }
+        return functions;
+    }
+
+    /// Disassemble a basic block and return its branch targets, with whether
+    /// the target is called.
+    fn block_targets(&self, addr: u64) -> Vec<(u64, bool)> {
+        // Blocks longer than this are split, the remaining is explored as
+        // another block.
+        const MAX_BLOCK_SIZE: usize = 0x1000;
+
+        let mut targets = Vec::new();
+        let code = match self.code_at(addr) {
+            Some(code) => code,
+            None => return targets,
+        };
+        let code = &code[..::std::cmp::min(code.len(), MAX_BLOCK_SIZE)];
+        let insts = match self.cs.disasm(code, addr, 0) {
+            Ok(insts) => insts,
+            Err(_) => return targets,
+        };
+
+        let mut next = addr;
+        for inst in insts.iter() {
+            next = inst.address + inst.size as u64;
+            let mnemonic = inst.mnemonic().unwrap_or("");
+            let target = inst.op_str().and_then(parse_imm);
+            match mnemonic {
+                "call" => {
+                    if let Some(target) = target {
+                        targets.push((target, true));
+                    }
+                }
+                "jmp" => {
+                    if let Some(target) = target {
+                        targets.push((target, false));
+                    }
+                    return targets;
+                }
+                "ret" | "retf" | "hlt" | "ud2" => return targets,
+                m if m.starts_with("j") || m.starts_with("loop") => {
+                    if let Some(target) = target {
+                        targets.push((target, false));
+                    }
+                    targets.push((next, false));
+                    return targets;
+                }
+                _ => (),
+            }
+        }
+
+        // The block was cut by the size limit or an invalid instruction,
+        // resume after the last decoded instruction.
+        if next != addr {
+            targets.push((next, false));
+        }
+        return targets;
+    }
+}
+
+/// Parse an immediate branch operand, such as `0x401000`.
+pub fn parse_imm(op_str: &str) -> Option<u64> {
+    if op_str.starts_with("0x") {
+        return u64::from_str_radix(&op_str[2..], 16).ok();
+    }
+    return op_str.parse().ok();
+}
@@ -1,2 +1,3 @@
 pub mod bin_file;
 pub mod cppfilt;
+pub mod discovery;
@@ -0,0 +1,276 @@
+use bin::discovery::parse_imm;
+use capstone;
+use dirt_engine::{FunctionInfo, TargetInfo};
+use emu;
+use emu::vmstate::VmState;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use utils::Fnv64;
+
+const CACHE_HEADER: &'static str = "dirt-cache 2";
+
+/// Cache of identification results, persisted on disk.
+///
+/// Results are keyed by `function_key`, so a function is only identified again
+/// when its code, the functions it references or the rules change.
+pub struct Cache {
+    path: PathBuf,
+    entries: HashMap<u64, Vec<FunctionInfo>>,
+    dirty: bool,
+}
+
+impl Cache {
+    /// Open the cache stored at |path|. A missing file is an empty cache.
+    pub fn open(path: &Path) -> io::Result<Cache> {
+        let mut cache = Cache {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            dirty: false,
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(cache)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header == CACHE_HEADER => (),
+            // Unknown cache version, start over.
+            _ => return Ok(cache),
+        }
+
+        // Each line is either a key alone, for functions without matches, or
+        // a key followed by one of its matches.
+        for line in lines {
+            let line = try!(line);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let key = match u64::from_str_radix(fields[0], 16) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            let matches = cache.entries.entry(key).or_insert(Vec::new());
+            if let Some(info) = parse_function_info(&fields[1..]) {
+                matches.push(info);
+            }
+        }
+        return Ok(cache);
+    }
+
+    pub fn get(&self, key: u64) -> Option<&Vec<FunctionInfo>> {
+        return self.entries.get(&key);
+    }
+
+    pub fn insert(&mut self, key: u64, matches: Vec<FunctionInfo>) {
+        self.entries.insert(key, matches);
+        self.dirty = true;
+    }
+
+    /// Write the cache back to its file, if it changed.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut out = try!(File::create(&self.path));
+        try!(writeln!(out, "{}", CACHE_HEADER));
+        let mut keys: Vec<&u64> = self.entries.keys().collect();
+        keys.sort();
+        for key in keys {
+            let matches = &self.entries[key];
+            if matches.is_empty() {
+                try!(writeln!(out, "{:016x}", key));
+            }
+            for m in matches {
+                try!(writeln!(out,
+                              "{:016x}\t{}\t{}\t{}\t{}\t{}\t{}",
+                              key,
+                              m.name,
+                              m.confidence,
+                              m.passed,
+                              m.total,
+                              join_indexes(&m.failed),
+                              join_indexes(&m.limited)));
+            }
+        }
+        self.dirty = false;
+        return Ok(());
+    }
+}
+
+fn join_indexes(indexes: &[usize]) -> String {
+    return indexes.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+}
+
+fn parse_indexes(field: &str) -> Result<Vec<usize>, ParseIntError> {
+    return field.split(',')
+        .filter(|i| !i.is_empty())
+        .map(|i| i.parse())
+        .collect();
+}
+
+fn parse_function_info(fields: &[&str]) -> Option<FunctionInfo> {
+    if fields.len() != 6 {
+        return None;
+    }
+    return match (fields[1].parse(),
+                  fields[2].parse(),
+                  fields[3].parse(),
+                  parse_indexes(fields[4]),
+                  parse_indexes(fields[5])) {
+        (Ok(confidence), Ok(passed), Ok(total), Ok(failed), Ok(limited)) => {
+            Some(FunctionInfo {
+                name: String::from(fields[0]),
+                confidence: confidence,
+                passed: passed,
+                total: total,
+                failed: failed,
+                limited: limited,
+            })
+        }
+        _ => None,
+    };
+}
+
+/// Compute the cache key of a function.
+///
+/// The key hashes the function's instructions and |salt|. Direct branches and
+/// RIP-relative operands are hashed as the name of the symbol they reference,
+/// or as an offset when they stay in the function, instead of their encoded
+/// displacement. This way, the key stays the same when the function is moved
+/// around by a rebuild. References without a symbol are hashed as their
+/// address, so that the callees of stripped functions are told apart, and
+/// the data referenced by RIP-relative operands is hashed along.
+pub fn function_key(vmstate: &VmState,
+                    target: &TargetInfo,
+                    salt: &[u8])
+                    -> Result<u64, emu::Error> {
+    let fva = vmstate.arch.code_addr(target.fva);
+    let code = try!(vmstate.engine
+        .borrow()
+        .mem_read(fva, target.size as usize));
+
+    let mut hasher = Fnv64::new();
+    hasher.write(salt);
+    hasher.write(&[0]);
+
+    let cs = capstone::Capstone::new(vmstate.arch.capstone_arch(),
+                                     vmstate.arch.capstone_mode_at(target.fva))
+        .expect("Failed to init capstone");
+    let insts = match cs.disasm(&code, fva, 0) {
+        Ok(insts) => insts,
+        Err(_) => {
+            hasher.write(&code);
+            return Ok(hasher.finish());
+        }
+    };
+
+    let reference_name = |addr: u64| -> String {
+        if addr >= fva && addr < fva + target.size {
+            return format!("+{:x}", addr - fva);
+        }
+        return match vmstate.object_info.function_at(vmstate.arch, addr) {
+            Some(name) => String::from(name),
+            None => format!("sub_{:x}", addr),
+        };
+    };
+
+    for inst in insts.iter() {
+        let mnemonic = inst.mnemonic().unwrap_or("");
+        let op_str = inst.op_str().unwrap_or("");
+        let offset = (inst.address - fva) as usize;
+        let next = inst.address + inst.size as u64;
+
+        hasher.write(mnemonic.as_bytes());
+        let is_branch = mnemonic == "call" || mnemonic.starts_with("j") ||
+                        mnemonic.starts_with("loop") ||
+                        mnemonic == "b" || mnemonic == "bl" ||
+                        mnemonic == "blx" ||
+                        mnemonic.starts_with("b.");
+        let branch = if is_branch {
+            parse_imm(op_str.trim_left_matches('#'))
+        } else {
+            None
+        };
+        if let Some(branch) = branch {
+            hasher.write(reference_name(branch).as_bytes());
+        } else if let Some((start, end, addr)) = rip_relative(op_str, next) {
+            hasher.write(op_str[..start].as_bytes());
+            hasher.write(reference_name(addr).as_bytes());
+            hasher.write(&referenced_data(vmstate, addr));
+            hasher.write(op_str[end..].as_bytes());
+        } else {
+            hasher.write(&code[offset..offset + inst.size as usize]);
+        }
+        hasher.write(&[0]);
+    }
+    return Ok(hasher.finish());
+}
+
+/// Size of the data hashed for a reference without a sized symbol.
+const DATA_KEY_SIZE: u64 = 0x40;
+/// Maximum size of the data hashed for a reference.
+const DATA_KEY_MAX_SIZE: u64 = 0x1000;
+
+/// Data referenced at |addr|: its symbol's content, or the bytes following it
+/// without a sized symbol, within its mapping.
+fn referenced_data(vmstate: &VmState, addr: u64) -> Vec<u8> {
+    let object_info = &vmstate.object_info;
+    let map_end = match object_info.mem_map_at(addr) {
+        Some(map) => map.addr + map.size as u64,
+        None => return Vec::new(),
+    };
+    let size = match object_info.symbol_at(addr)
+        .and_then(|name| object_info.symbols.get(name)) {
+        Some(symbol) if symbol.size > 0 => symbol.size,
+        _ => DATA_KEY_SIZE,
+    };
+    let size = ::std::cmp::min(::std::cmp::min(size, DATA_KEY_MAX_SIZE),
+                               map_end - addr);
+    return vmstate.engine
+        .borrow()
+        .mem_read(addr, size as usize)
+        .unwrap_or(Vec::new());
+}
+
+/// Find a `[rip + disp]` operand in |op_str|. Returns the operand's bounds
+/// and the address it references.
+fn rip_relative(op_str: &str, next: u64) -> Option<(usize, usize, u64)> {
+    let start = match op_str.find("[rip ") {
+        Some(start) => start,
+        None => return None,
+    };
+    let end = match op_str[start..].find(']') {
+        Some(end) => start + end + 1,
+        None => return None,
+    };
+    let operand = &op_str[start + 5..end - 1];
+    let (negative, disp) = if operand.starts_with("+ ") {
+        (false, &operand[2..])
+    } else if operand.starts_with("- ") {
+        (true, &operand[2..])
+    } else {
+        return None;
+    };
+    let disp = match parse_imm(disp) {
+        Some(disp) => disp,
+        None => return None,
+    };
+    let addr = if negative {
+        next.wrapping_sub(disp)
+    } else {
+        next.wrapping_add(disp)
+    };
+    return Some((start, end, addr));
+}
@@ -1,12 +1,22 @@
+use cache;
+use cache::Cache;
 use emu;
+use emu::arch::Arch;
 use emu::emu_engine::EmuEngine;
 use emu::debugger::Debugger;
 use rules::Rule;
 use rules::RuleSet;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
 
 #[derive(Debug)]
 pub enum Error {
     EmuError(emu::Error),
+    /// The worker identifying the function panicked.
+    WorkerPanicked,
 }
 
 impl ::std::convert::From<emu::Error> for Error {
@@ -15,9 +25,40 @@ impl ::std::convert::From<emu::Error> for Error {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum CallingConvention {
+    Cdecl,
     Stdcall,
+    Fastcall,
+    Thiscall,
     SystemV,
+    Win64,
+    Aapcs,
+    /// AAPCS with floating point numbers in the VFP registers, used by the
+    /// hard-float binaries.
+    AapcsVfp,
+    Aapcs64,
+    O32,
+}
+
+impl FromStr for CallingConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CallingConvention, String> {
+        match s {
+            "cdecl" => Ok(CallingConvention::Cdecl),
+            "stdcall" => Ok(CallingConvention::Stdcall),
+            "fastcall" => Ok(CallingConvention::Fastcall),
+            "thiscall" => Ok(CallingConvention::Thiscall),
+            "systemv" => Ok(CallingConvention::SystemV),
+            "win64" => Ok(CallingConvention::Win64),
+            "aapcs" => Ok(CallingConvention::Aapcs),
+            "aapcs-vfp" => Ok(CallingConvention::AapcsVfp),
+            "aapcs64" => Ok(CallingConvention::Aapcs64),
+            "o32" => Ok(CallingConvention::O32),
+            _ => Err(format!("Unknown calling convention: {}", s)),
+        }
+    }
 }
 
 /// DirtEngine is the glue code between the rules, emulation and function list
@@ -28,95 +69,484 @@ pub struct DirtEngine {
     /// Rules loaded in the current context.
     ruleset: Box<RuleSet>,
     debugger: Debugger,
+    /// Candidates scoring below this confidence are not reported.
+    min_confidence: f64,
+    /// Identification results of previous runs, shared between engines.
+    cache: Option<Arc<Mutex<Cache>>>,
+    /// Rules grouped by identical arguments, each rule designated by its
+    /// candidate name and index. A function is emulated once per group.
+    rule_groups: Vec<Vec<(String, usize)>>,
 }
 
 /// TargetInfo contains the information about a function to be sent and
 /// identified by DIRT.
+#[derive(Clone, Copy, Debug)]
 pub struct TargetInfo {
     pub fva: u64,
+    /// Size of the function, 0 if unknown.
+    pub size: u64,
     pub cc: CallingConvention,
 }
 
+/// Default minimum confidence for a candidate to be reported as a match:
+/// it must verify all its rules, partial matches are opted into.
+pub const DEFAULT_MIN_CONFIDENCE: f64 = 1.0;
+
 /// Function info contains the information of a succesful identification.
+#[derive(Clone, Debug)]
 pub struct FunctionInfo {
     pub name: String,
+    /// Weighted ratio of the candidate's rules verified by the function, from
+    /// 0 to 1.
+    pub confidence: f64,
+    /// Number of rules verified by the function.
+    pub passed: usize,
+    /// Number of rules of the candidate.
+    pub total: usize,
+    /// Index of the rules not verified by the function.
+    pub failed: Vec<usize>,
+    /// Index of the rules whose emulation exceeded its limits. They are
+    /// neither passed nor failed.
+    pub limited: Vec<usize>,
 }
 
-enum CallError {
-    /// Error that happened while emulating an unknown function. Expected to
-    /// happen often and likely to be a lead for negative.
-    NotMatched,
-    /// Error that happened while setting up the emulation engine. Must be
-    /// handled.
-    EmuError(emu::Error),
+impl FunctionInfo {
+    /// Whether the function verified every rules of the candidate.
+    pub fn is_exact(&self) -> bool {
+        return self.passed == self.total;
+    }
+}
+
+/// Identification is the outcome of the identification of a function by a
+/// worker of `identify_parallel`.
+pub struct Identification {
+    /// Index of the function in the targets given to `identify_parallel`.
+    pub index: usize,
+    pub target: TargetInfo,
+    pub result: Result<Vec<FunctionInfo>, Error>,
+    /// Number of emulated calls it took to identify the function.
+    pub emulations: usize,
+}
+
+/// RuleOutcome is the result of a rule against a function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleOutcome {
+    Passed,
+    Failed,
+    /// The function didn't return within the rule's emulation limits.
+    LimitExceeded,
+    /// The rule's arguments don't fit in the arguments data, so the function
+    /// wasn't called. Counted as failed.
+    InputTooLarge,
+}
+
+/// RuleEval is the outcome of a single candidate's rule against a function.
+pub struct RuleEval {
+    pub candidate: String,
+    /// Index of the rule in the candidate's rules.
+    pub index: usize,
+    pub outcome: RuleOutcome,
 }
 
 impl DirtEngine {
     /// Create a new DirtEngine given an emulation engine and ruleset.
     pub fn new(emu: EmuEngine, ruleset: Box<RuleSet>) -> DirtEngine {
         let debugger_engine = emu.vmstate.engine.clone();
+        let arch = emu.vmstate.arch;
+        let rule_groups = group_rules(&ruleset);
         return DirtEngine {
             emu: emu,
             ruleset: ruleset,
-            debugger: Debugger::new(debugger_engine),
+            debugger: Debugger::new(debugger_engine, arch),
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            cache: None,
+            rule_groups: rule_groups,
         };
     }
 
-    /// Identify a single function.
-    #[allow(unused_variables)]
+    /// Reuse and record identification results in |cache|. Only functions
+    /// with a known size are cached.
+    pub fn set_cache(&mut self, cache: Arc<Mutex<Cache>>) {
+        self.cache = Some(cache);
+    }
+
+    /// Set the minimum confidence of the candidates reported by
+    /// `identify_function`. With a confidence of 1, only candidates whose
+    /// every rules are verified are reported.
+    pub fn set_min_confidence(&mut self, min_confidence: f64) {
+        self.min_confidence = min_confidence;
+    }
+
+    /// Identify a single function. Returns every candidate scoring at least
+    /// the minimum confidence, best first.
     pub fn identify_function(&mut self,
                              target: &TargetInfo)
                              -> Result<Vec<FunctionInfo>, Error> {
+        let key = match self.cache {
+            Some(ref cache) if target.size > 0 => {
+                let key = try!(self.cache_key(target));
+                if let Some(matches) = cache.lock().unwrap().get(key) {
+                    return Ok(matches.clone());
+                }
+                Some(key)
+            }
+            _ => None,
+        };
+
+        let matches = try!(self.identify_candidates(target));
+        if let (Some(key), Some(cache)) = (key, self.cache.as_ref()) {
+            cache.lock().unwrap().insert(key, matches.clone());
+        }
+        return Ok(matches);
+    }
+
+    /// Cache key of a function, also covering everything else the
+    /// identification depends on.
+    fn cache_key(&self, target: &TargetInfo) -> Result<u64, emu::Error> {
+        let salt = format!("{:x}:{}:{:?}:{:?}:{:?}",
+                           self.ruleset.version(),
+                           self.min_confidence,
+                           target.cc,
+                           self.emu.limits(),
+                           self.emu.vmstate.init_strategy);
+        return cache::function_key(&self.emu.vmstate,
+                                   target,
+                                   salt.as_bytes());
+    }
+
+    fn identify_candidates(&mut self,
+                           target: &TargetInfo)
+                           -> Result<Vec<FunctionInfo>, Error> {
+        let min_confidence = self.min_confidence;
+        let results = try!(self.run_rules(target, Some(min_confidence)));
+
         let mut matches = Vec::new();
-        let debugger = &mut self.debugger;
-        let emu = &mut self.emu;
-        // Iterate through each candidate's rules.
         for (candidate_name, rules) in self.ruleset.candidates() {
-            // For each target rules, get a list of the input argument to be
-            // emulated and run the unknown function. Check with the rule if the
-            // result match its conditions.
-            let call_result: Result<Vec<()>, CallError> = rules.iter()
-                .map(|rule| {
-                    // println!("Calling {}({:?})", rule.name, rule.args);
-                    // debugger.detach().expect("Failed to detach debugger");
-                    // if rule.name == "std::string::string(char*)" && false {
-                    //    debugger.attach().expect("Failed to attach debugger");
-                    // }
-                    return match emu.call(target, &rule.args) {
-                        Ok(call_effects) => {
-                            if rule.verify(&call_effects) {
-                                Ok(())
-                            } else {
-                                Err(CallError::NotMatched)
-                            }
-                        }
-                        Err(emu::Error::ExecError(e)) => {
-                            // println!("ExecError: {:?}", e);
-                            Err(CallError::NotMatched)
-                        }
-                        Err(e) => Err(CallError::EmuError(e)),
-                    };
+            let outcomes = &results[candidate_name];
+            let total_weight: f64 =
+                rules.iter().map(|r| r.options().weight).sum();
+            let passed_weight: f64 = rules.iter()
+                .zip(outcomes)
+                .filter(|&(_, outcome)| *outcome == Some(RuleOutcome::Passed))
+                .map(|(r, _)| r.options().weight)
+                .sum();
+            let with_outcome = |expected: RuleOutcome| -> Vec<usize> {
+                outcomes.iter()
+                    .enumerate()
+                    .filter(|&(_, outcome)| *outcome == Some(expected))
+                    .map(|(index, _)| index)
+                    .collect()
+            };
+            let mut failed = with_outcome(RuleOutcome::Failed);
+            failed.extend(with_outcome(RuleOutcome::InputTooLarge));
+            failed.sort();
+            let limited = with_outcome(RuleOutcome::LimitExceeded);
+            let passed = with_outcome(RuleOutcome::Passed).len();
+
+            let confidence = if total_weight > 0.0 {
+                passed_weight / total_weight
+            } else {
+                0.0
+            };
+            if passed > 0 && confidence >= min_confidence {
+                matches.push(FunctionInfo {
+                    name: candidate_name.clone(),
+                    confidence: confidence,
+                    passed: passed,
+                    total: rules.len(),
+                    failed: failed,
+                    limited: limited,
+                });
+            }
+        }
+
+        matches.sort_by(|a, b| {
+            (b.confidence, &a.name)
+                .partial_cmp(&(a.confidence, &b.name))
+                .unwrap()
+        });
+        return Ok(matches);
+    }
+
+    /// Run every rule of every candidate against a function and return each
+    /// rule's outcome, without stopping at the first mismatch.
+    pub fn eval_function(&mut self,
+                         target: &TargetInfo)
+                         -> Result<Vec<RuleEval>, Error> {
+        let results = try!(self.run_rules(target, None));
+
+        let mut evals = Vec::new();
+        for (candidate_name, outcomes) in results {
+            for (index, outcome) in outcomes.into_iter().enumerate() {
+                evals.push(RuleEval {
+                    candidate: candidate_name.clone(),
+                    index: index,
+                    outcome: outcome.unwrap_or(RuleOutcome::Failed),
+                });
+            }
+        }
+        return Ok(evals);
+    }
+
+    /// Run the candidates' rules against a function. The function is emulated
+    /// once per group of rules sharing the same arguments, and each rule of
+    /// the group verifies the same effects.
+    ///
+    /// With |min_confidence|, the rules of a candidate are skipped as soon as
+    /// it can't reach this confidence anymore. Returns the outcome of each
+    /// candidate's rules, None for the skipped ones.
+    fn run_rules(&mut self,
+                 target: &TargetInfo,
+                 min_confidence: Option<f64>)
+                 -> Result<HashMap<String, Vec<Option<RuleOutcome>>>, Error> {
+        let candidates = self.ruleset.candidates();
+        let mut results: HashMap<String, Vec<Option<RuleOutcome>>> = candidates
+            .iter()
+            .map(|(name, rules)| (name.clone(), vec![None; rules.len()]))
+            .collect();
+        let emu = &mut self.emu;
+
+        for group in &self.rule_groups {
+            let pending: Vec<&(String, usize)> = group.iter()
+                .filter(|&&(ref name, _)| match min_confidence {
+                    Some(min_confidence) => {
+                        can_reach(&candidates[name],
+                                  &results[name],
+                                  min_confidence)
+                    }
+                    None => true,
                 })
                 .collect();
+            let (name, index) = match pending.first() {
+                Some(&&(ref name, index)) => (name, index),
+                None => continue,
+            };
 
-            match call_result {
-                Ok(_) => {
-                    matches.push(FunctionInfo { name: candidate_name.clone() })
+            let trace_calls = pending.iter()
+                .any(|&&(ref name, index)| {
+                    candidates[name][index].options().trace_calls
+                });
+            try!(emu.set_call_tracing(trace_calls));
+
+            // Every rule of the group has the same arguments and limits.
+            let rule = &candidates[name][index];
+            let limits = rule.options().limits(emu.limits());
+            let effects = match emu.call_with_limits(target,
+                                                     rule.args(),
+                                                     limits) {
+                Ok(effects) => effects,
+                // The arguments are the rules' own problem, not the
+                // function's.
+                Err(emu::Error::EmuDataOverflow) => {
+                    for &&(ref name, index) in &pending {
+                        results.get_mut(name).unwrap()[index] =
+                            Some(RuleOutcome::InputTooLarge);
+                    }
+                    continue;
                 }
-                Err(CallError::NotMatched) => (),
-                Err(CallError::EmuError(e)) => return Err(Error::EmuError(e)),
+                Err(e) => return Err(Error::from(e)),
             };
+            for &&(ref name, index) in &pending {
+                let rule = &candidates[name][index];
+                let expect_fault = rule.options().expect_fault;
+                let outcome = match effects.fault {
+                    Some(ref fault) if fault.is_limit() => {
+                        RuleOutcome::LimitExceeded
+                    }
+                    ref fault if fault.is_some() != expect_fault => {
+                        RuleOutcome::Failed
+                    }
+                    _ if rule.verify(&effects) => RuleOutcome::Passed,
+                    _ => RuleOutcome::Failed,
+                };
+                results.get_mut(name).unwrap()[index] = Some(outcome);
+            }
         }
-        return Ok(matches);
+        return Ok(results);
     }
 
     /// Helper function, returns the default calling convention for the target
     /// plateform.
     pub fn default_cc(&self) -> CallingConvention {
-        CallingConvention::SystemV
+        match self.emu.vmstate.arch {
+            Arch::X86 => CallingConvention::Cdecl,
+            Arch::X86_64 => CallingConvention::SystemV,
+            Arch::Arm if self.emu.vmstate.object_info.hard_float => {
+                CallingConvention::AapcsVfp
+            }
+            Arch::Arm => CallingConvention::Aapcs,
+            Arch::Arm64 => CallingConvention::Aapcs64,
+            Arch::Mips | Arch::Mipsel => CallingConvention::O32,
+        }
     }
 
     pub fn emu(&self) -> &EmuEngine {
         &self.emu
     }
+
+    pub fn emu_mut(&mut self) -> &mut EmuEngine {
+        &mut self.emu
+    }
+
+    pub fn ruleset(&self) -> &RuleSet {
+        &self.ruleset
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+}
+
+/// Group the rules of |ruleset| by identical arguments and limits, in
+/// candidate name then rule order.
+fn group_rules(ruleset: &RuleSet) -> Vec<Vec<(String, usize)>> {
+    let candidates = ruleset.candidates();
+    let mut names: Vec<&String> = candidates.keys().collect();
+    names.sort();
+
+    let mut groups: Vec<Vec<(String, usize)>> = Vec::new();
+    let mut group_by_args = HashMap::new();
+    for name in names {
+        for (index, rule) in candidates[name].iter().enumerate() {
+            let next_group = groups.len();
+            let key = format!("{} {:?} {:?}",
+                              rule.args().key(),
+                              rule.options().timeout,
+                              rule.options().max_count);
+            let group = *group_by_args.entry(key).or_insert(next_group);
+            if group == next_group {
+                groups.push(Vec::new());
+            }
+            groups[group].push((name.clone(), index));
+        }
+    }
+    return groups;
+}
+
+/// Whether a candidate can still reach |min_confidence|, given the outcome of
+/// its rules so far.
+fn can_reach<R: Rule>(rules: &[R],
+                      outcomes: &[Option<RuleOutcome>],
+                      min_confidence: f64)
+                      -> bool {
+    let total_weight: f64 = rules.iter().map(|r| r.options().weight).sum();
+    let reachable_weight: f64 = rules.iter()
+        .zip(outcomes)
+        .filter(|&(_, outcome)| match *outcome {
+            Some(RuleOutcome::Passed) | None => true,
+            Some(_) => false,
+        })
+        .map(|(r, _)| r.options().weight)
+        .sum();
+    return reachable_weight >= min_confidence * total_weight;
+}
+
+/// Identify |targets| across |jobs| worker threads.
+///
+/// Emulators and rulesets can't be shared between threads, so |dirt| works on
+/// the calling thread and each other worker creates its own DirtEngine with
+/// |factory|. They pull functions from a shared queue until it is empty.
+/// |progress| is called on the calling thread as soon as a function is
+/// identified. The functions of a worker that panicked are reported with
+/// `Error::WorkerPanicked`. The results are returned in address order.
+pub fn identify_parallel<F, P>(mut dirt: DirtEngine,
+                               factory: F,
+                               targets: Vec<TargetInfo>,
+                               jobs: usize,
+                               mut progress: P)
+                               -> Vec<Identification>
+    where F: Fn() -> DirtEngine + Send + Sync + 'static,
+          P: FnMut(&Identification)
+{
+    let factory = Arc::new(factory);
+    let queue = Arc::new(Mutex::new(targets.clone().into_iter().enumerate()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (1..jobs)
+        .map(|_| {
+            let factory = factory.clone();
+            let queue = queue.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut dirt = factory();
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let (index, target) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let identification = identify(&mut dirt, index, target);
+                    tx.send(identification)
+                        .expect("Failed to send identification");
+                }
+            })
+        })
+        .collect();
+    // Only the workers' senders must keep the channel open.
+    drop(tx);
+
+    let mut identifications = Vec::new();
+    loop {
+        let next = queue.lock().unwrap().next();
+        let (index, target) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        let identification = identify(&mut dirt, index, target);
+        progress(&identification);
+        identifications.push(identification);
+        for identification in rx.try_iter() {
+            progress(&identification);
+            identifications.push(identification);
+        }
+    }
+    for identification in rx {
+        progress(&identification);
+        identifications.push(identification);
+    }
+
+    let mut panicked = false;
+    for worker in workers {
+        panicked = worker.join().is_err() || panicked;
+    }
+    if panicked {
+        // The functions a worker was identifying when it panicked were
+        // taken from the queue but never sent.
+        let mut identified = vec![false; targets.len()];
+        for identification in &identifications {
+            identified[identification.index] = true;
+        }
+        for (index, target) in targets.into_iter().enumerate() {
+            if !identified[index] {
+                let identification = Identification {
+                    index: index,
+                    target: target,
+                    result: Err(Error::WorkerPanicked),
+                    emulations: 0,
+                };
+                progress(&identification);
+                identifications.push(identification);
+            }
+        }
+    }
+
+    identifications.sort_by(|a, b| {
+        (a.target.fva, a.index).cmp(&(b.target.fva, b.index))
+    });
+    return identifications;
+}
+
+/// Identify the function |target|, the |index|-th of `identify_parallel`.
+fn identify(dirt: &mut DirtEngine,
+            index: usize,
+            target: TargetInfo)
+            -> Identification {
+    let emu_counter = dirt.emu().emu_counter();
+    let result = dirt.identify_function(&target);
+    return Identification {
+        index: index,
+        target: target,
+        result: result,
+        emulations: dirt.emu().emu_counter() - emu_counter,
+    };
 }
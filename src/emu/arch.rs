@@ -0,0 +1,269 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use capstone;
+use elf;
+use unicorn;
+use unicorn::arm_const::RegisterARM;
+use unicorn::arm64_const::RegisterARM64;
+use unicorn::mips_const::RegisterMIPS;
+use unicorn::x86_const::RegisterX86;
+
+/// Endian is the byte order of the emulated memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn read_u16(&self, data: &[u8]) -> u16 {
+        return match *self {
+            Endian::Little => LittleEndian::read_u16(data),
+            Endian::Big => BigEndian::read_u16(data),
+        };
+    }
+
+    pub fn read_u32(&self, data: &[u8]) -> u32 {
+        return match *self {
+            Endian::Little => LittleEndian::read_u32(data),
+            Endian::Big => BigEndian::read_u32(data),
+        };
+    }
+
+    pub fn read_u64(&self, data: &[u8]) -> u64 {
+        return match *self {
+            Endian::Little => LittleEndian::read_u64(data),
+            Endian::Big => BigEndian::read_u64(data),
+        };
+    }
+
+    pub fn write_u32(&self, data: &mut [u8], n: u32) {
+        match *self {
+            Endian::Little => LittleEndian::write_u32(data, n),
+            Endian::Big => BigEndian::write_u32(data, n),
+        }
+    }
+
+    pub fn write_u64(&self, data: &mut [u8], n: u64) {
+        match *self {
+            Endian::Little => LittleEndian::write_u64(data, n),
+            Endian::Big => BigEndian::write_u64(data, n),
+        }
+    }
+
+    /// Split the 64 bits |value| in two 32 bits words, in memory order.
+    pub fn split_u64(&self, value: u64) -> [u64; 2] {
+        let (low, high) = (value & 0xffffffff, value >> 32);
+        return match *self {
+            Endian::Little => [low, high],
+            Endian::Big => [high, low],
+        };
+    }
+}
+
+/// Arch is the architecture of the emulated binary. It abstracts the
+/// registers and data layout the emulator depends on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    X86,
+    X86_64,
+    Arm,
+    Arm64,
+    /// Big endian MIPS32.
+    Mips,
+    /// Little endian MIPS32.
+    Mipsel,
+}
+
+impl Arch {
+    /// Returns the architecture of an ELF machine type and byte order, if
+    /// supported.
+    pub fn from_elf(machine: elf::types::Machine,
+                    data: elf::types::Data)
+                    -> Option<Arch> {
+        let big_endian = data == elf::types::ELFDATA2MSB;
+        return match machine {
+            elf::types::EM_MIPS if big_endian => Some(Arch::Mips),
+            elf::types::EM_MIPS => Some(Arch::Mipsel),
+            _ if big_endian => None,
+            elf::types::EM_386 => Some(Arch::X86),
+            elf::types::EM_X86_64 => Some(Arch::X86_64),
+            elf::types::EM_ARM => Some(Arch::Arm),
+            elf::types::EM_AARCH64 => Some(Arch::Arm64),
+            _ => None,
+        };
+    }
+
+    pub fn endian(&self) -> Endian {
+        return match *self {
+            Arch::Mips => Endian::Big,
+            _ => Endian::Little,
+        };
+    }
+
+    pub fn unicorn_arch(&self) -> unicorn::unicorn_const::Arch {
+        return match *self {
+            Arch::X86 | Arch::X86_64 => unicorn::unicorn_const::Arch::X86,
+            Arch::Arm => unicorn::unicorn_const::Arch::ARM,
+            Arch::Arm64 => unicorn::unicorn_const::Arch::ARM64,
+            Arch::Mips | Arch::Mipsel => unicorn::unicorn_const::Arch::MIPS,
+        };
+    }
+
+    pub fn unicorn_mode(&self) -> unicorn::unicorn_const::Mode {
+        return match *self {
+            Arch::X86 => unicorn::unicorn_const::Mode::MODE_32,
+            Arch::X86_64 => unicorn::unicorn_const::Mode::MODE_64,
+            Arch::Arm | Arch::Arm64 => {
+                unicorn::unicorn_const::Mode::MODE_ARM
+            }
+            Arch::Mips => {
+                unicorn::unicorn_const::Mode::MODE_32 |
+                unicorn::unicorn_const::Mode::BIG_ENDIAN
+            }
+            Arch::Mipsel => unicorn::unicorn_const::Mode::MODE_32,
+        };
+    }
+
+    pub fn capstone_arch(&self) -> capstone::CsArch {
+        return match *self {
+            Arch::X86 | Arch::X86_64 => capstone::CsArch::ARCH_X86,
+            Arch::Arm => capstone::CsArch::ARCH_ARM,
+            Arch::Arm64 => capstone::CsArch::ARCH_ARM64,
+            Arch::Mips | Arch::Mipsel => capstone::CsArch::ARCH_MIPS,
+        };
+    }
+
+    pub fn capstone_mode(&self) -> capstone::CsMode {
+        return match *self {
+            Arch::X86 => capstone::CsMode::MODE_32,
+            Arch::X86_64 => capstone::CsMode::MODE_64,
+            Arch::Arm | Arch::Arm64 => capstone::CsMode::MODE_ARM,
+            // MIPS32 is implied.
+            Arch::Mips => capstone::CsMode::MODE_BIG_ENDIAN,
+            Arch::Mipsel => capstone::CsMode::MODE_32,
+        };
+    }
+
+    /// Capstone mode of the code at |addr|, which is Thumb for the odd
+    /// addresses on ARM.
+    pub fn capstone_mode_at(&self, addr: u64) -> capstone::CsMode {
+        if self.is_thumb(addr) {
+            return capstone::CsMode::MODE_THUMB;
+        }
+        return self.capstone_mode();
+    }
+
+    /// Whether |addr| points to Thumb code. ARM sets the lowest bit of the
+    /// Thumb functions' addresses.
+    pub fn is_thumb(&self, addr: u64) -> bool {
+        return *self == Arch::Arm && addr & 1 != 0;
+    }
+
+    /// Whether |engine| is executing Thumb code.
+    pub fn in_thumb(&self, engine: &unicorn::Unicorn) -> bool {
+        if *self != Arch::Arm {
+            return false;
+        }
+        return match engine.reg_read(RegisterARM::CPSR as i32) {
+            Ok(cpsr) => cpsr & 0x20 != 0,
+            Err(_) => false,
+        };
+    }
+
+    /// Address of the code at |addr|, without the Thumb bit.
+    pub fn code_addr(&self, addr: u64) -> u64 {
+        return match *self {
+            Arch::Arm => addr & !1,
+            _ => addr,
+        };
+    }
+
+    /// Size of a pointer, in bytes.
+    pub fn pointer_size(&self) -> usize {
+        return match *self {
+            Arch::X86 | Arch::Arm | Arch::Mips | Arch::Mipsel => 4,
+            Arch::X86_64 | Arch::Arm64 => 8,
+        };
+    }
+
+    pub fn sp_reg(&self) -> i32 {
+        return match *self {
+            Arch::X86 => RegisterX86::ESP as i32,
+            Arch::X86_64 => RegisterX86::RSP as i32,
+            Arch::Arm => RegisterARM::SP as i32,
+            Arch::Arm64 => RegisterARM64::SP as i32,
+            Arch::Mips | Arch::Mipsel => RegisterMIPS::SP as i32,
+        };
+    }
+
+    pub fn ip_reg(&self) -> i32 {
+        return match *self {
+            Arch::X86 => RegisterX86::EIP as i32,
+            Arch::X86_64 => RegisterX86::RIP as i32,
+            Arch::Arm => RegisterARM::PC as i32,
+            Arch::Arm64 => RegisterARM64::PC as i32,
+            Arch::Mips | Arch::Mipsel => RegisterMIPS::PC as i32,
+        };
+    }
+
+    /// Register holding the integer return value of a function.
+    pub fn return_reg(&self) -> i32 {
+        return match *self {
+            Arch::X86 => RegisterX86::EAX as i32,
+            Arch::X86_64 => RegisterX86::RAX as i32,
+            Arch::Arm => RegisterARM::R0 as i32,
+            Arch::Arm64 => RegisterARM64::X0 as i32,
+            Arch::Mips | Arch::Mipsel => RegisterMIPS::V0 as i32,
+        };
+    }
+
+    /// Register holding the high half of a value returned in two registers,
+    /// such as small structs.
+    pub fn return_high_reg(&self) -> i32 {
+        return match *self {
+            Arch::X86 => RegisterX86::EDX as i32,
+            Arch::X86_64 => RegisterX86::RDX as i32,
+            Arch::Arm => RegisterARM::R1 as i32,
+            Arch::Arm64 => RegisterARM64::X1 as i32,
+            Arch::Mips | Arch::Mipsel => RegisterMIPS::V1 as i32,
+        };
+    }
+
+    /// Register holding the return address on calls, for the architectures
+    /// that don't push it on the stack.
+    pub fn link_reg(&self) -> Option<i32> {
+        return match *self {
+            Arch::X86 | Arch::X86_64 => None,
+            Arch::Arm => Some(RegisterARM::LR as i32),
+            Arch::Arm64 => Some(RegisterARM64::X30 as i32),
+            Arch::Mips | Arch::Mipsel => Some(RegisterMIPS::RA as i32),
+        };
+    }
+
+    /// Register expected to hold the address of the called function. MIPS
+    /// position independent code computes its global pointer from $t9.
+    pub fn entry_reg(&self) -> Option<i32> {
+        return match *self {
+            Arch::Mips | Arch::Mipsel => Some(RegisterMIPS::T9 as i32),
+            _ => None,
+        };
+    }
+
+    /// Pack |value| as a pointer sized integer.
+    pub fn pack(&self, value: u64) -> Vec<u8> {
+        let mut packed = vec![0; self.pointer_size()];
+        match self.pointer_size() {
+            4 => self.endian().write_u32(&mut packed, value as u32),
+            _ => self.endian().write_u64(&mut packed, value),
+        }
+        return packed;
+    }
+
+    /// Unpack a pointer sized integer from |data|.
+    pub fn unpack(&self, data: &[u8]) -> u64 {
+        return match self.pointer_size() {
+            4 => self.endian().read_u32(data) as u64,
+            _ => self.endian().read_u64(data),
+        };
+    }
+}
@@ -1,45 +1,238 @@
+use emu;
 use emu::Error;
-use emu::vmstate::VmState;
+use emu::dirty_pages::PAGE_SIZE;
+use emu::vmstate::{DataWriter, VmState};
 use emu::datatypes::DataType;
 use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct EmuArgs {
     argv: Vec<Rc<DataType>>,
+    ret: ReturnType,
+    guard: GuardMode,
+}
+
+/// ReturnType is how the called function gives back its result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReturnType {
+    /// A single value, in the return register.
+    Scalar,
+    /// A small struct split in the return register and the next one, such
+    /// as rax:rdx.
+    Pair,
+    /// A struct of the given size, written by the callee to a buffer given by
+    /// a hidden pointer argument.
+    Struct(u64),
+}
+
+/// GuardMode is how the arguments are laid out against guard pages, which the
+/// called function can't access without it being reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuardMode {
+    /// The arguments are packed together.
+    Off,
+    /// Each argument ends against a guard page, catching any overflow and the
+    /// underflows reaching the previous page.
+    Overflow,
+    /// Each argument starts against a guard page, catching any underflow and
+    /// the overflows reaching the next page.
+    Underflow,
+}
+
+/// GuardedArg is the data of an argument laid out against guard pages.
+#[derive(Clone, Copy, Debug)]
+pub struct GuardedArg {
+    /// Index of the argument.
+    pub index: usize,
+    pub addr: u64,
+    pub size: u64,
 }
 
 pub struct PushableArg(Rc<DataType>, u64);
 
+/// ArgValue is the value of an argument, as given to the calling convention.
+#[derive(Clone, Copy, Debug)]
+pub enum ArgValue {
+    Integer(u64),
+    /// Raw bits of a single precision floating point number.
+    Float(u64),
+    /// Raw bits of a double precision floating point number.
+    Double(u64),
+}
+
+impl ArgValue {
+    /// Raw value of the argument, as it is pushed on the stack.
+    pub fn raw(&self) -> u64 {
+        return match *self {
+            ArgValue::Integer(value) |
+            ArgValue::Float(value) |
+            ArgValue::Double(value) => value,
+        };
+    }
+
+    pub fn is_float(&self) -> bool {
+        return match *self {
+            ArgValue::Integer(_) => false,
+            ArgValue::Float(_) | ArgValue::Double(_) => true,
+        };
+    }
+}
+
 pub struct PushableArgs {
     argv: Vec<PushableArg>,
+    ret: ReturnType,
+    sret: Option<u64>,
+    guarded: Vec<GuardedArg>,
+    guard_pages: Vec<u64>,
 }
 
 impl EmuArgs {
     pub fn new(argv: Vec<Rc<DataType>>) -> EmuArgs {
-        return EmuArgs { argv: argv };
+        return EmuArgs {
+            argv: argv,
+            ret: ReturnType::Scalar,
+            guard: GuardMode::Off,
+        };
+    }
+
+    pub fn set_return_type(&mut self, ret: ReturnType) {
+        self.ret = ret;
     }
 
+    pub fn set_guard_mode(&mut self, guard: GuardMode) {
+        self.guard = guard;
+    }
+
+    /// Structural description of the arguments. Arguments with equal keys
+    /// give the same emulation.
+    pub fn key(&self) -> String {
+        let argv: Vec<String> = self.argv.iter().map(|a| a.key()).collect();
+        let mut key = argv.join(", ");
+        if self.ret != ReturnType::Scalar {
+            key.push_str(&format!(" -> {:?}", self.ret));
+        }
+        if self.guard != GuardMode::Off {
+            key.push_str(&format!(" guard {:?}", self.guard));
+        }
+        return key;
+    }
+
+    /// Write the arguments data, up to |max_data| bytes, and return the
+    /// values to pass to the function. Each argument gets its own aligned
+    /// allocation, or its own pages with the guard pages.
     pub fn as_pushable(&self,
-                       vmstate: &VmState)
+                       vmstate: &VmState,
+                       max_data: usize)
                        -> Result<PushableArgs, Error> {
-        let mut data_writer = try!(vmstate.emudata_writer());
-        let argv: Result<Vec<_>, Error> = self.argv
-            .iter()
-            .map(|a| {
-                Ok(PushableArg(a.clone(),
-                               try!(a.pushable_value(&mut data_writer))))
-            })
-            .collect();
-        return Ok(PushableArgs { argv: try!(argv) });
+        let mut data_writer = try!(vmstate.emudata_writer(max_data));
+        let sret = match self.ret {
+            ReturnType::Struct(size) => {
+                Some(try!(data_writer.write_data(&vec![0; size as usize])))
+            }
+            _ => None,
+        };
+
+        let mut argv = Vec::new();
+        let mut guarded = Vec::new();
+        let mut guard_pages = Vec::new();
+        if self.guard != GuardMode::Off {
+            guard_pages.push(try!(data_writer.guard_page()));
+        }
+        for (index, a) in self.argv.iter().enumerate() {
+            if self.guard == GuardMode::Off {
+                try!(data_writer.align(emu::EMUDATA_ALIGN));
+                let value = try!(a.pushable_value(&mut data_writer));
+                argv.push(PushableArg(a.clone(), value));
+                continue;
+            }
+
+            let (value, data) = try!(write_guarded(a,
+                                                   self.guard,
+                                                   &mut data_writer));
+            argv.push(PushableArg(a.clone(), value));
+            if let Some((addr, size)) = data {
+                guarded.push(GuardedArg {
+                    index: index,
+                    addr: addr,
+                    size: size,
+                });
+                guard_pages.push(try!(data_writer.guard_page()));
+            }
+        }
+        return Ok(PushableArgs {
+            argv: argv,
+            ret: self.ret,
+            sret: sret,
+            guarded: guarded,
+            guard_pages: guard_pages,
+        });
+    }
+}
+
+/// Write |arg| against a guard page, on the side given by |mode|. The writer
+/// must be at the start of a page. Returns the value to pass to the function
+/// and the address and size of the argument's data, if it has any.
+fn write_guarded(arg: &Rc<DataType>,
+                 mode: GuardMode,
+                 data_writer: &mut DataWriter)
+                 -> Result<(u64, Option<(u64, u64)>), Error> {
+    // The data is written once to learn its size, then again at its place.
+    let start = data_writer.current_ptr();
+    let value = try!(arg.pushable_value(data_writer));
+    let size = data_writer.current_ptr() - start;
+    if size == 0 {
+        return Ok((value, None));
     }
+    data_writer.seek(start);
+    try!(data_writer.write_data(&vec![0; size as usize]));
+
+    let addr = match mode {
+        GuardMode::Overflow => {
+            let end = (start + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            end - size
+        }
+        _ => start,
+    };
+    data_writer.seek(addr);
+    let value = try!(arg.pushable_value(data_writer));
+    return Ok((value, Some((addr, size))));
 }
 
 impl PushableArgs {
-    pub fn pushed_args(&self) -> Vec<u64> {
-        return self.argv.iter().map(|&PushableArg(_, v)| v).collect();
+    pub fn pushed_args(&self) -> Vec<ArgValue> {
+        return self.argv
+            .iter()
+            .map(|&PushableArg(ref data, v)| if data.is_double() {
+                ArgValue::Double(v)
+            } else if data.is_float() {
+                ArgValue::Float(v)
+            } else {
+                ArgValue::Integer(v)
+            })
+            .collect();
     }
 
     pub fn nth(&self, n: usize) -> u64 {
         return self.argv[n].1;
     }
+
+    pub fn return_type(&self) -> ReturnType {
+        return self.ret;
+    }
+
+    /// Address of the buffer the returned struct is written to, passed as a
+    /// hidden argument.
+    pub fn sret(&self) -> Option<u64> {
+        return self.sret;
+    }
+
+    /// Arguments laid out against guard pages.
+    pub fn guarded(&self) -> &[GuardedArg] {
+        return &self.guarded;
+    }
+
+    /// Guard pages around the arguments, to protect during the call.
+    pub fn guard_pages(&self) -> &[u64] {
+        return &self.guard_pages;
+    }
 }
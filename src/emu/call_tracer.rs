@@ -0,0 +1,297 @@
+use emu::Error;
+use emu::arch::Arch;
+use byteorder::{ByteOrder, LittleEndian};
+use emu::object_info::ObjectInfo;
+use std::cell::RefCell;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::CodeHookType;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Call,
+    Return,
+}
+
+/// CallEdge is a call or a return taken by an emulated call.
+#[derive(Clone, Debug)]
+pub struct CallEdge {
+    pub kind: EdgeKind,
+    /// Address of the call or ret instruction.
+    pub from: u64,
+    /// Address of the callee, or the address returned to.
+    pub to: u64,
+    /// Number of calls not returned from when the edge was taken. The traced
+    /// function's own calls are at depth 0.
+    pub depth: usize,
+    /// Name of the callee, for calls to a known symbol.
+    pub symbol: Option<String>,
+}
+
+/// Instruction executed before the current one, if it is a call or a return.
+struct LastInsn {
+    kind: Option<EdgeKind>,
+    address: u64,
+    /// Whether the branch is taken after a delay slot, which is still to be
+    /// executed.
+    delayed: bool,
+}
+
+struct TraceState {
+    last: LastInsn,
+    depth: usize,
+    edges: Vec<CallEdge>,
+}
+
+/// CallTracer records the dynamic call graph of the emulated code.
+///
+/// Every instruction is hooked, so tracing is only enabled on demand. An edge
+/// is recorded once the instruction following a call or a ret is executed,
+/// which misses the traced function's final return.
+pub struct CallTracer {
+    hook: Option<unicorn::uc_hook>,
+    state: Rc<RefCell<TraceState>>,
+    engine: Rc<RefCell<unicorn::Unicorn>>,
+    arch: Arch,
+}
+
+impl CallTracer {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>,
+               arch: Arch)
+               -> CallTracer {
+        return CallTracer {
+            hook: None,
+            state: Rc::new(RefCell::new(TraceState {
+                last: LastInsn {
+                    kind: None,
+                    address: 0,
+                    delayed: false,
+                },
+                depth: 0,
+                edges: Vec::new(),
+            })),
+            engine: engine,
+            arch: arch,
+        };
+    }
+
+    pub fn attach(&mut self) -> Result<(), Error> {
+        if let Some(_) = self.hook {
+            return Ok(());
+        }
+
+        let state = self.state.clone();
+        let arch = self.arch;
+        self.hook = Some(try!(self.engine
+            .borrow_mut()
+            .add_code_hook(CodeHookType::CODE,
+                           1,
+                           0,
+                           move |engine, address, size| {
+                CallTracer::on_code(engine, arch, &state, address, size)
+            })));
+        return Ok(());
+    }
+
+    pub fn detach(&mut self) -> Result<(), Error> {
+        if let Some(hook) = self.hook {
+            try!(self.engine.borrow_mut().remove_hook(hook));
+            self.hook = None;
+        }
+        return Ok(());
+    }
+
+    /// Forget the recorded edges.
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.last.kind = None;
+        state.depth = 0;
+        state.edges.clear();
+    }
+
+    /// Returns the edges taken since the last reset, with the callees
+    /// symbolized through |object_info|.
+    pub fn edges(&self, object_info: &ObjectInfo) -> Vec<CallEdge> {
+        return self.state
+            .borrow()
+            .edges
+            .iter()
+            .map(|edge| {
+                let mut edge = edge.clone();
+                if edge.kind == EdgeKind::Call {
+                    edge.symbol = object_info.function_at(self.arch, edge.to)
+                        .map(String::from);
+                }
+                edge
+            })
+            .collect();
+    }
+
+    fn on_code(engine: &unicorn::Unicorn,
+               arch: Arch,
+               state: &RefCell<TraceState>,
+               address: u64,
+               size: u32) {
+        let mut state = state.borrow_mut();
+        if state.last.delayed {
+            state.last.delayed = false;
+            return;
+        }
+        if let Some(kind) = state.last.kind {
+            let from = state.last.address;
+            let depth = match kind {
+                EdgeKind::Call => {
+                    state.depth += 1;
+                    state.depth - 1
+                }
+                EdgeKind::Return => {
+                    if state.depth > 0 {
+                        state.depth -= 1;
+                    }
+                    state.depth
+                }
+            };
+            state.edges.push(CallEdge {
+                kind: kind,
+                from: from,
+                to: address,
+                depth: depth,
+                symbol: None,
+            });
+        }
+
+        let kind = match engine.mem_read(address, size as usize) {
+            Ok(code) => branch_kind(engine, arch, &code),
+            Err(_) => None,
+        };
+        state.last = LastInsn {
+            kind: kind,
+            address: address,
+            delayed: kind.is_some() &&
+                     (arch == Arch::Mips || arch == Arch::Mipsel),
+        };
+    }
+}
+
+/// Returns whether the instruction |code| being executed by |engine| is a
+/// call or a return.
+pub fn branch_kind(engine: &unicorn::Unicorn,
+                   arch: Arch,
+                   code: &[u8])
+                   -> Option<EdgeKind> {
+    return match arch {
+        Arch::X86 | Arch::X86_64 => x86_branch_kind(arch, code),
+        Arch::Arm if arch.in_thumb(engine) => thumb_branch_kind(code),
+        Arch::Arm => arm_branch_kind(code),
+        Arch::Arm64 => arm64_branch_kind(code),
+        Arch::Mips | Arch::Mipsel => mips_branch_kind(arch, code),
+    };
+}
+
+/// Returns whether the x86 instruction |code| is a call or a ret.
+fn x86_branch_kind(arch: Arch, code: &[u8]) -> Option<EdgeKind> {
+    // Skip the legacy and REX prefixes. 0x40-0x4f are inc and dec in 32 bits.
+    let opcode = code.iter().position(|&b| {
+        match b {
+            0x66 | 0x67 | 0xf2 | 0xf3 => false,
+            0x40...0x4f => arch != Arch::X86_64,
+            _ => true,
+        }
+    });
+    let opcode = match opcode {
+        Some(i) => &code[i..],
+        None => return None,
+    };
+    return match opcode[0] {
+        0xe8 => Some(EdgeKind::Call),
+        // call r/m is ff /2.
+        0xff if opcode.len() > 1 && (opcode[1] >> 3) & 7 == 2 => {
+            Some(EdgeKind::Call)
+        }
+        0xc2 | 0xc3 => Some(EdgeKind::Return),
+        _ => None,
+    };
+}
+
+/// Returns whether the ARM instruction |code| is a call or a return.
+fn arm_branch_kind(code: &[u8]) -> Option<EdgeKind> {
+    if code.len() < 4 {
+        return None;
+    }
+    let insn = LittleEndian::read_u32(code);
+    // bl, blx imm and blx reg.
+    if (insn & 0x0f000000 == 0x0b000000 && insn >> 28 != 0xf) ||
+       insn & 0xfe000000 == 0xfa000000 ||
+       insn & 0x0ffffff0 == 0x012fff30 {
+        return Some(EdgeKind::Call);
+    }
+    // bx lr, pop {..., pc} and ldr pc, [sp], #4.
+    if insn & 0x0fffffff == 0x012fff1e || insn & 0x0fff8000 == 0x08bd8000 ||
+       insn & 0x0fffffff == 0x049df004 {
+        return Some(EdgeKind::Return);
+    }
+    return None;
+}
+
+/// Returns whether the Thumb instruction |code| is a call or a return.
+fn thumb_branch_kind(code: &[u8]) -> Option<EdgeKind> {
+    if code.len() < 2 {
+        return None;
+    }
+    let hw = LittleEndian::read_u16(code);
+    if code.len() >= 4 {
+        // bl and blx imm.
+        let hw2 = LittleEndian::read_u16(&code[2..]);
+        if hw & 0xf800 == 0xf000 && hw2 & 0xc000 == 0xc000 {
+            return Some(EdgeKind::Call);
+        }
+        // pop.w {..., pc}
+        if hw == 0xe8bd && hw2 & 0x8000 != 0 {
+            return Some(EdgeKind::Return);
+        }
+        return None;
+    }
+    return match hw {
+        // blx reg.
+        hw if hw & 0xff87 == 0x4780 => Some(EdgeKind::Call),
+        // bx lr and pop {..., pc}.
+        0x4770 => Some(EdgeKind::Return),
+        hw if hw & 0xff00 == 0xbd00 => Some(EdgeKind::Return),
+        _ => None,
+    };
+}
+
+/// Returns whether the AArch64 instruction |code| is a call or a return.
+fn arm64_branch_kind(code: &[u8]) -> Option<EdgeKind> {
+    if code.len() < 4 {
+        return None;
+    }
+    let insn = LittleEndian::read_u32(code);
+    // bl and blr.
+    if insn & 0xfc000000 == 0x94000000 || insn & 0xfffffc1f == 0xd63f0000 {
+        return Some(EdgeKind::Call);
+    }
+    // ret.
+    if insn & 0xfffffc1f == 0xd65f0000 {
+        return Some(EdgeKind::Return);
+    }
+    return None;
+}
+
+/// Returns whether the MIPS instruction |code| is a call or a return.
+fn mips_branch_kind(arch: Arch, code: &[u8]) -> Option<EdgeKind> {
+    if code.len() < 4 {
+        return None;
+    }
+    let insn = arch.endian().read_u32(code);
+    // jal, jalr and bal.
+    if insn >> 26 == 3 || insn & 0xfc00003f == 0x00000009 ||
+       insn & 0xffff0000 == 0x04110000 {
+        return Some(EdgeKind::Call);
+    }
+    // jr ra.
+    if insn == 0x03e00008 {
+        return Some(EdgeKind::Return);
+    }
+    return None;
+}
@@ -1,58 +1,483 @@
 use emu::Error;
+use emu::arch::Arch;
+use emu::args::ArgValue;
 use emu::vmstate::VmState;
 use dirt_engine::CallingConvention as CCEnum;
+use unicorn::arm_const::RegisterARM;
+use unicorn::arm64_const::RegisterARM64;
+use unicorn::mips_const::RegisterMIPS;
 use unicorn::x86_const::RegisterX86;
 
 pub trait CallingConvention {
-    fn init_args(&self, args: &[u64], vmstate: &VmState) -> Result<(), Error>;
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error>;
+
+    /// Pass |sret|, the address of the buffer receiving a returned struct.
+    /// Most conventions give it as a hidden first argument.
+    fn init_sret(&self,
+                 sret: u64,
+                 args: &mut Vec<ArgValue>,
+                 _vmstate: &VmState)
+                 -> Result<(), Error> {
+        args.insert(0, ArgValue::Integer(sret));
+        return Ok(());
+    }
 }
 
+/// Arguments pushed right to left, cleaned up by the caller.
+struct Cdecl;
+/// Arguments pushed right to left, cleaned up by the callee with `ret n`.
 struct Stdcall;
+/// The first two integer arguments in ecx and edx, the others as stdcall.
+struct Fastcall;
+/// `this` in ecx, the other arguments as stdcall.
+struct Thiscall;
 struct SystemV;
+/// Microsoft x64 convention, used by mingw and Wine builds.
+struct Win64;
+/// ARM procedure call standard, with floating point numbers passed in the
+/// core registers as in the soft-float ABI.
+struct Aapcs;
+/// ARM procedure call standard with floating point numbers passed in the VFP
+/// registers, as in the hard-float ABI.
+struct AapcsVfp;
+struct Aapcs64;
+/// MIPS o32, with floating point numbers passed in the integer registers as
+/// in the soft-float ABI.
+struct O32;
 
-impl CallingConvention for Stdcall {
-    fn init_args(&self, args: &[u64], vmstate: &VmState) -> Result<(), Error> {
-        for arg in args {
-            try!(vmstate.stack_push(*arg));
+/// Push |args| on the stack, right to left. On 32 bits architectures, doubles
+/// take two slots.
+fn push_stack_args(args: &[ArgValue], vmstate: &VmState) -> Result<(), Error> {
+    for arg in args.iter().rev() {
+        match *arg {
+            ArgValue::Double(value) if vmstate.arch.pointer_size() == 4 => {
+                let words = vmstate.arch.endian().split_u64(value);
+                try!(vmstate.stack_push(words[1]));
+                try!(vmstate.stack_push(words[0]));
+            }
+            _ => try!(vmstate.stack_push(arg.raw())),
+        }
+    }
+    return Ok(());
+}
+
+/// Pass the integer arguments in |regs| while they are available, and the
+/// others on the stack.
+fn init_register_args(regs: &[i32],
+                      args: &[ArgValue],
+                      vmstate: &VmState)
+                      -> Result<(), Error> {
+    let mut regs = regs.iter();
+    let mut stack_args = Vec::new();
+    for arg in args {
+        match *arg {
+            ArgValue::Integer(value) => {
+                match regs.next() {
+                    Some(&reg) => {
+                        try!(vmstate.engine
+                            .borrow()
+                            .reg_write(reg, value))
+                    }
+                    None => stack_args.push(*arg),
+                }
+            }
+            _ => stack_args.push(*arg),
         }
+    }
+    return push_stack_args(&stack_args, vmstate);
+}
+
+impl CallingConvention for Cdecl {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        return push_stack_args(args, vmstate);
+    }
+}
+
+impl CallingConvention for Stdcall {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // The callee pops its arguments, which does not matter since the
+        // stack is reset before every call.
+        return push_stack_args(args, vmstate);
+    }
+}
+
+const FASTCALL_REGS: [i32; 2] = [RegisterX86::ECX as i32,
+                                 RegisterX86::EDX as i32];
+
+impl CallingConvention for Fastcall {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        return init_register_args(&FASTCALL_REGS, args, vmstate);
+    }
+}
+
+const THISCALL_REGS: [i32; 1] = [RegisterX86::ECX as i32];
+
+impl CallingConvention for Thiscall {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        return init_register_args(&THISCALL_REGS, args, vmstate);
+    }
+
+    fn init_sret(&self,
+                 sret: u64,
+                 args: &mut Vec<ArgValue>,
+                 _vmstate: &VmState)
+                 -> Result<(), Error> {
+        // `this` keeps ecx, the buffer is the first stack argument.
+        let n = ::std::cmp::min(args.len(), 1);
+        args.insert(n, ArgValue::Integer(sret));
         return Ok(());
     }
 }
 
+const SYSTEMV_INT_REGS: [i32; 6] = [RegisterX86::RDI as i32,
+                                    RegisterX86::RSI as i32,
+                                    RegisterX86::RDX as i32,
+                                    RegisterX86::RCX as i32,
+                                    RegisterX86::R8 as i32,
+                                    RegisterX86::R9 as i32];
+const SYSTEMV_FLOAT_REGS: u8 = 8;
+
 impl CallingConvention for SystemV {
-    fn init_args(&self, args: &[u64], vmstate: &VmState) -> Result<(), Error> {
-        let engine = vmstate.engine.borrow();
-        let mut args_iter = args.into_iter();
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::RDI as i32, *arg));
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // Integers go in the general purpose registers and floating point
+        // numbers in xmm0-7, the others on the stack.
+        let mut int_regs = SYSTEMV_INT_REGS.iter();
+        let mut float_regs = 0..SYSTEMV_FLOAT_REGS;
+        let mut float_count = 0;
+        let mut stack_args = Vec::new();
+        for arg in args {
+            if arg.is_float() {
+                match float_regs.next() {
+                    Some(n) => {
+                        try!(vmstate.set_xmm(n, arg.raw()));
+                        float_count += 1;
+                    }
+                    None => stack_args.push(*arg),
+                }
+            } else {
+                match int_regs.next() {
+                    Some(&reg) => {
+                        try!(vmstate.engine
+                            .borrow()
+                            .reg_write(reg, arg.raw()))
+                    }
+                    None => stack_args.push(*arg),
+                }
+            }
+        }
+
+        // Variadic functions expect the number of vector registers used in al.
+        try!(vmstate.engine
+            .borrow()
+            .reg_write(RegisterX86::RAX as i32, float_count));
+
+        return push_stack_args(&stack_args, vmstate);
+    }
+}
+
+const WIN64_INT_REGS: [i32; 4] = [RegisterX86::RCX as i32,
+                                  RegisterX86::RDX as i32,
+                                  RegisterX86::R8 as i32,
+                                  RegisterX86::R9 as i32];
+/// Bytes reserved above the return address for the callee to spill the
+/// register arguments.
+const WIN64_SHADOW_SPACE: usize = 32;
+
+impl CallingConvention for Win64 {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // The first four arguments go in rcx, rdx, r8 and r9, or xmm0-3 for
+        // floating point numbers, by position. Floating point numbers are
+        // also given in the integer registers for variadic functions.
+        for (n, arg) in args.iter().take(WIN64_INT_REGS.len()).enumerate() {
+            if arg.is_float() {
+                try!(vmstate.set_xmm(n as u8, arg.raw()));
+            }
+            try!(vmstate.engine
+                .borrow()
+                .reg_write(WIN64_INT_REGS[n], arg.raw()));
         }
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::RSI as i32, *arg));
+
+        // Keep rsp aligned on 16 bytes once the shadow space is reserved.
+        let stack_args = &args[::std::cmp::min(args.len(),
+                                               WIN64_INT_REGS.len())..];
+        if stack_args.len() % 2 != 0 {
+            try!(vmstate.stack_push(0));
+        }
+        try!(push_stack_args(stack_args, vmstate));
+        for _ in 0..WIN64_SHADOW_SPACE / 8 {
+            try!(vmstate.stack_push(0));
         }
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::RDX as i32, *arg));
+        return Ok(());
+    }
+}
+
+const AAPCS_REGS: [i32; 4] = [RegisterARM::R0 as i32,
+                              RegisterARM::R1 as i32,
+                              RegisterARM::R2 as i32,
+                              RegisterARM::R3 as i32];
+
+impl CallingConvention for Aapcs {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        let args: Vec<(ArgValue, bool)> =
+            args.iter().map(|&arg| (arg, true)).collect();
+        return init_aapcs_core_args(&args, vmstate);
+    }
+}
+
+/// Pass the arguments in r0-r3, then on the stack, in order. Doubles take an
+/// even and odd register pair. The arguments not marked as fitting the core
+/// registers always go on the stack.
+fn init_aapcs_core_args(args: &[(ArgValue, bool)],
+                        vmstate: &VmState)
+                        -> Result<(), Error> {
+    let engine = vmstate.engine.borrow();
+    let mut next_reg = 0;
+    let mut stack_args = Vec::new();
+    for &(arg, core) in args {
+        match arg {
+            _ if !core => stack_args.push(arg),
+            ArgValue::Double(value) => {
+                next_reg += next_reg % 2;
+                if next_reg + 1 < AAPCS_REGS.len() {
+                    try!(engine.reg_write(AAPCS_REGS[next_reg],
+                                          value & 0xffffffff));
+                    try!(engine.reg_write(AAPCS_REGS[next_reg + 1],
+                                          value >> 32));
+                    next_reg += 2;
+                } else {
+                    next_reg = AAPCS_REGS.len();
+                    stack_args.push(arg);
+                }
+            }
+            _ => {
+                if next_reg < AAPCS_REGS.len() {
+                    try!(engine.reg_write(AAPCS_REGS[next_reg], arg.raw()));
+                    next_reg += 1;
+                } else {
+                    stack_args.push(arg);
+                }
+            }
         }
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::RCX as i32, *arg));
+    }
+    return push_stack_args(&stack_args, vmstate);
+}
+
+const AAPCS_VFP_REGS: [i32; 8] = [RegisterARM::D0 as i32,
+                                  RegisterARM::D1 as i32,
+                                  RegisterARM::D2 as i32,
+                                  RegisterARM::D3 as i32,
+                                  RegisterARM::D4 as i32,
+                                  RegisterARM::D5 as i32,
+                                  RegisterARM::D6 as i32,
+                                  RegisterARM::D7 as i32];
+
+impl CallingConvention for AapcsVfp {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // Floats take the first free single register of s0-s15 and doubles
+        // the first free double register of d0-d7, which overlap: sN is a
+        // half of dN/2. Once one doesn't fit, the following ones go on the
+        // stack. The other arguments are passed as with the soft-float ABI.
+        let mut used = [false; 16];
+        let mut core_args = Vec::new();
+        for &arg in args {
+            let slot = match arg {
+                ArgValue::Float(_) => (0..16).find(|&s| !used[s]),
+                ArgValue::Double(_) => {
+                    (0..16).find(|&s| s % 2 == 0 && !used[s] && !used[s + 1])
+                }
+                ArgValue::Integer(_) => {
+                    core_args.push((arg, true));
+                    continue;
+                }
+            };
+            match slot {
+                Some(s) => {
+                    used[s] = true;
+                    if let ArgValue::Double(value) = arg {
+                        used[s + 1] = true;
+                        try!(set_vfp_double(vmstate, s / 2, value));
+                    } else {
+                        try!(set_vfp_single(vmstate, s, arg.raw()));
+                    }
+                }
+                None => {
+                    used = [true; 16];
+                    core_args.push((arg, false));
+                }
+            }
         }
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::R8 as i32, *arg));
+        return init_aapcs_core_args(&core_args, vmstate);
+    }
+}
+
+fn set_vfp_double(vmstate: &VmState,
+                  n: usize,
+                  value: u64)
+                  -> Result<(), Error> {
+    return vmstate.engine
+        .borrow()
+        .reg_write(AAPCS_VFP_REGS[n], value)
+        .map_err(|e| Error::UnicornError(e));
+}
+
+/// Set the single register sN, the low half of dN/2 when N is even and its
+/// high half otherwise.
+fn set_vfp_single(vmstate: &VmState,
+                  n: usize,
+                  value: u64)
+                  -> Result<(), Error> {
+    let reg = AAPCS_VFP_REGS[n / 2];
+    let double = try!(vmstate.engine.borrow().reg_read(reg));
+    let double = if n % 2 == 0 {
+        (double & !0xffffffff) | (value & 0xffffffff)
+    } else {
+        (double & 0xffffffff) | (value << 32)
+    };
+    return set_vfp_double(vmstate, n / 2, double);
+}
+
+const AAPCS64_INT_REGS: [i32; 8] = [RegisterARM64::X0 as i32,
+                                    RegisterARM64::X1 as i32,
+                                    RegisterARM64::X2 as i32,
+                                    RegisterARM64::X3 as i32,
+                                    RegisterARM64::X4 as i32,
+                                    RegisterARM64::X5 as i32,
+                                    RegisterARM64::X6 as i32,
+                                    RegisterARM64::X7 as i32];
+const AAPCS64_FLOAT_REGS: [i32; 8] = [RegisterARM64::D0 as i32,
+                                      RegisterARM64::D1 as i32,
+                                      RegisterARM64::D2 as i32,
+                                      RegisterARM64::D3 as i32,
+                                      RegisterARM64::D4 as i32,
+                                      RegisterARM64::D5 as i32,
+                                      RegisterARM64::D6 as i32,
+                                      RegisterARM64::D7 as i32];
+
+impl CallingConvention for Aapcs64 {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // Integers go in x0-x7 and floating point numbers in v0-v7, the
+        // others on the stack.
+        let engine = vmstate.engine.borrow();
+        let mut int_regs = AAPCS64_INT_REGS.iter();
+        let mut float_regs = AAPCS64_FLOAT_REGS.iter();
+        let mut stack_args = Vec::new();
+        for arg in args {
+            let reg = if arg.is_float() {
+                float_regs.next()
+            } else {
+                int_regs.next()
+            };
+            match reg {
+                Some(&reg) => try!(engine.reg_write(reg, arg.raw())),
+                None => stack_args.push(*arg),
+            }
         }
-        if let Some(arg) = args_iter.next() {
-            try!(engine.reg_write(RegisterX86::R9 as i32, *arg));
+
+        // Keep sp aligned on 16 bytes.
+        if stack_args.len() % 2 != 0 {
+            try!(vmstate.stack_push(0));
         }
+        return push_stack_args(&stack_args, vmstate);
+    }
 
-        for arg in args_iter {
-            try!(vmstate.stack_push(*arg));
+    fn init_sret(&self,
+                 sret: u64,
+                 _args: &mut Vec<ArgValue>,
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // The buffer is given in x8 and doesn't take an argument register.
+        return vmstate.engine
+            .borrow()
+            .reg_write(RegisterARM64::X8 as i32, sret)
+            .map_err(|e| Error::UnicornError(e));
+    }
+}
+
+const O32_REGS: [i32; 4] = [RegisterMIPS::A0 as i32,
+                            RegisterMIPS::A1 as i32,
+                            RegisterMIPS::A2 as i32,
+                            RegisterMIPS::A3 as i32];
+
+impl CallingConvention for O32 {
+    fn init_args(&self,
+                 args: &[ArgValue],
+                 vmstate: &VmState)
+                 -> Result<(), Error> {
+        // Arguments are laid out in 4 bytes slots, with doubles aligned on 8
+        // bytes. The first four slots are passed in a0-a3, but their space is
+        // still reserved on the stack.
+        let endian = vmstate.arch.endian();
+        let mut slots = Vec::new();
+        for arg in args {
+            match *arg {
+                ArgValue::Double(value) => {
+                    if slots.len() % 2 != 0 {
+                        slots.push(0);
+                    }
+                    slots.extend(&endian.split_u64(value));
+                }
+                _ => slots.push(arg.raw()),
+            }
+        }
+        while slots.len() < O32_REGS.len() {
+            slots.push(0);
         }
 
+        for (&reg, &slot) in O32_REGS.iter().zip(&slots) {
+            try!(vmstate.engine.borrow().reg_write(reg, slot));
+        }
+        // Keep sp aligned on 8 bytes.
+        if slots.len() % 2 != 0 {
+            try!(vmstate.stack_push(0));
+        }
+        for &slot in slots.iter().rev() {
+            try!(vmstate.stack_push(slot));
+        }
         return Ok(());
     }
 }
 
 pub fn new(cc: &CCEnum) -> Box<CallingConvention> {
     return match cc {
+        &CCEnum::Cdecl => Box::new(Cdecl {}),
         &CCEnum::Stdcall => Box::new(Stdcall {}),
+        &CCEnum::Fastcall => Box::new(Fastcall {}),
+        &CCEnum::Thiscall => Box::new(Thiscall {}),
         &CCEnum::SystemV => Box::new(SystemV {}),
+        &CCEnum::Win64 => Box::new(Win64 {}),
+        &CCEnum::Aapcs => Box::new(Aapcs {}),
+        &CCEnum::AapcsVfp => Box::new(AapcsVfp {}),
+        &CCEnum::Aapcs64 => Box::new(Aapcs64 {}),
+        &CCEnum::O32 => Box::new(O32 {}),
     };
 }
@@ -13,6 +13,19 @@ pub trait DataType: ::std::fmt::Debug {
                       data_writer: &mut DataWriter)
                       -> Result<u64, Error>;
     fn write_value(&self, data_writer: &mut DataWriter) -> Result<(), Error>;
+    /// Structural description of the data. Data with equal keys are written
+    /// the same way in the emulator.
+    fn key(&self) -> String;
+    /// Whether the data is passed in floating point registers. Its pushable
+    /// value is then the raw bits of the number.
+    fn is_float(&self) -> bool {
+        return false;
+    }
+    /// Whether the data is a double precision floating point number, which
+    /// takes two stack slots on 32 bits architectures.
+    fn is_double(&self) -> bool {
+        return false;
+    }
 }
 
 #[derive(Debug)]
@@ -31,6 +44,10 @@ impl DataType for StringData {
         try!(self.pushable_value(data_writer));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        return format!("Str({:?})", self.value);
+    }
 }
 
 impl StringData {
@@ -60,6 +77,10 @@ impl DataType for BufData {
         try!(self.pushable_value(data_writer));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        return format!("Buf({}, {:?})", self.size, self.data);
+    }
 }
 
 impl BufData {
@@ -83,6 +104,10 @@ impl DataType for IntegerData {
         try!(data_writer.write_usize(self.0));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        return format!("Int({})", self.0);
+    }
 }
 
 #[derive(Debug)]
@@ -106,6 +131,11 @@ impl DataType for CompositeData {
         try!(self.pushable_value(data_writer));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        let fields: Vec<String> = self.fields.iter().map(|f| f.key()).collect();
+        return format!("Composite[{}]", fields.join(", "));
+    }
 }
 
 impl CompositeData {
@@ -129,6 +159,10 @@ impl DataType for ThisOffsetData {
         try!(data_writer.write_usize(value));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        return format!("This({})", self.0);
+    }
 }
 
 #[derive(Debug)]
@@ -143,4 +177,74 @@ impl DataType for ByteData {
         try!(data_writer.write_data(&[self.0]));
         return Ok(());
     }
+
+    fn key(&self) -> String {
+        return format!("Byte({})", self.0);
+    }
+}
+
+/// Single precision floating point number.
+#[derive(Debug)]
+pub struct FloatData(pub f32);
+
+impl FloatData {
+    fn bits(&self) -> u32 {
+        return self.0.to_bits();
+    }
+}
+
+impl DataType for FloatData {
+    fn pushable_value(&self, _: &mut DataWriter) -> Result<u64, Error> {
+        return Ok(self.bits() as u64);
+    }
+
+    fn write_value(&self, data_writer: &mut DataWriter) -> Result<(), Error> {
+        let mut data = [0; 4];
+        data_writer.endian().write_u32(&mut data, self.bits());
+        try!(data_writer.write_data(&data));
+        return Ok(());
+    }
+
+    fn key(&self) -> String {
+        return format!("Float({:x})", self.bits());
+    }
+
+    fn is_float(&self) -> bool {
+        return true;
+    }
+}
+
+/// Double precision floating point number.
+#[derive(Debug)]
+pub struct DoubleData(pub f64);
+
+impl DoubleData {
+    fn bits(&self) -> u64 {
+        return self.0.to_bits();
+    }
+}
+
+impl DataType for DoubleData {
+    fn pushable_value(&self, _: &mut DataWriter) -> Result<u64, Error> {
+        return Ok(self.bits());
+    }
+
+    fn write_value(&self, data_writer: &mut DataWriter) -> Result<(), Error> {
+        let mut data = [0; 8];
+        data_writer.endian().write_u64(&mut data, self.bits());
+        try!(data_writer.write_data(&data));
+        return Ok(());
+    }
+
+    fn key(&self) -> String {
+        return format!("Double({:x})", self.bits());
+    }
+
+    fn is_float(&self) -> bool {
+        return true;
+    }
+
+    fn is_double(&self) -> bool {
+        return true;
+    }
 }
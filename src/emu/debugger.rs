@@ -1,5 +1,6 @@
 use capstone;
 use emu::Error;
+use emu::arch::Arch;
 use std::rc::Rc;
 use std::cell::RefCell;
 use unicorn;
@@ -9,19 +10,23 @@ pub struct Debugger {
     code_hook: Option<unicorn::uc_hook>,
     mem_hook: Option<unicorn::uc_hook>,
     engine: Rc<RefCell<unicorn::Unicorn>>,
+    arch: Arch,
 }
 
-pub fn attach(engine: Rc<RefCell<unicorn::Unicorn>>) -> Result<Debugger, Error> {
-    let mut debugger = Debugger::new(engine);
+pub fn attach(engine: Rc<RefCell<unicorn::Unicorn>>,
+              arch: Arch)
+              -> Result<Debugger, Error> {
+    let mut debugger = Debugger::new(engine, arch);
     return debugger.attach().and(Ok(debugger));
 }
 
 impl Debugger {
-    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>) -> Debugger {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>, arch: Arch) -> Debugger {
         return Debugger {
             code_hook: None,
             mem_hook: None,
             engine: engine,
+            arch: arch,
         };
     }
 
@@ -30,13 +35,14 @@ impl Debugger {
             return Ok(());
         }
 
+        let arch = self.arch;
         self.code_hook = Some(try!(self.engine
             .borrow_mut()
             .add_code_hook(CodeHookType::CODE,
                            1,
                            0,
-                           |engine, address, size| {
-                               Debugger::on_code(engine, address, size)
+                           move |engine, address, size| {
+                               Debugger::on_code(engine, arch, address, size)
                            })));
         self.mem_hook = Some(try!(self.engine
             .borrow_mut()
@@ -62,9 +68,13 @@ impl Debugger {
         return Ok(());
     }
 
-    fn on_code(engine: &unicorn::Unicorn, address: u64, size: u32) {
-        let cs = capstone::Capstone::new(capstone::CsArch::ARCH_X86,
-                                         capstone::CsMode::MODE_64)
+    fn on_code(engine: &unicorn::Unicorn, arch: Arch, address: u64, size: u32) {
+        let mode = if arch.in_thumb(engine) {
+            capstone::CsMode::MODE_THUMB
+        } else {
+            arch.capstone_mode()
+        };
+        let cs = capstone::Capstone::new(arch.capstone_arch(), mode)
             .expect("Failed to init capstone");
         let code = engine.mem_read(address, size as usize)
             .expect("Failed to read code memory");
@@ -0,0 +1,75 @@
+use emu::Error;
+use emu::shadow_memory::ShadowMemory;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::MemHookType;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// DirtyPages keeps track of the pages written since the last snapshot, so
+/// only those need to be restored between calls.
+///
+/// Writes done by the emulated code are caught by a memory hook. Writes done
+/// through the engine don't trigger hooks and must be marked explicitly.
+/// Written bytes are also initialized in the shadow memory.
+#[derive(Clone)]
+pub struct DirtyPages {
+    /// Address of each dirty page.
+    pages: Rc<RefCell<BTreeSet<u64>>>,
+    shadow: ShadowMemory,
+}
+
+impl DirtyPages {
+    pub fn new() -> DirtyPages {
+        return DirtyPages {
+            pages: Default::default(),
+            shadow: ShadowMemory::new(),
+        };
+    }
+
+    pub fn shadow(&self) -> &ShadowMemory {
+        return &self.shadow;
+    }
+
+    /// Mark the pages of [addr, addr + size) as dirty whenever |engine|
+    /// emulates a write.
+    pub fn attach(&self,
+                  engine: &Rc<RefCell<unicorn::Unicorn>>)
+                  -> Result<unicorn::uc_hook, Error> {
+        let dirty_pages = self.clone();
+        let hook = try!(engine.borrow_mut()
+            .add_mem_hook(MemHookType::MEM_WRITE,
+                          1,
+                          0,
+                          move |_, _, address, size, _| {
+                dirty_pages.mark(address, size);
+                return true;
+            }));
+        return Ok(hook);
+    }
+
+    /// Mark the pages of [addr, addr + size) as dirty, and its bytes as
+    /// initialized.
+    pub fn mark(&self, addr: u64, size: usize) {
+        if size == 0 {
+            return;
+        }
+        self.shadow.mark(addr, size);
+        let mut pages = self.pages.borrow_mut();
+        let first = addr & !(PAGE_SIZE - 1);
+        let last = (addr + size as u64 - 1) & !(PAGE_SIZE - 1);
+        let mut page = first;
+        while page <= last {
+            pages.insert(page);
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Returns the dirty pages address and mark every page clean.
+    pub fn take(&self) -> BTreeSet<u64> {
+        return ::std::mem::replace(&mut *self.pages.borrow_mut(),
+                                   BTreeSet::new());
+    }
+}
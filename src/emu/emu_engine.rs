@@ -1,18 +1,141 @@
 use dirt_engine::TargetInfo;
 use emu;
-use emu::Error;
+use emu::{EmuLimits, Error};
+use emu::arch::{Arch, Endian};
 use emu::args::{EmuArgs, PushableArgs};
+use emu::call_tracer::{CallEdge, CallTracer, EdgeKind};
+use emu::env::SyscallRecord;
+use emu::fault::Fault;
+use emu::guard_pages::{GuardPages, OutOfBounds};
+use emu::heap_tracker::{Allocator, HeapEffects, HeapTracker};
+use emu::mem_tracker::{MemTracker, MemWrite};
+use emu::shadow_memory::UninitRead;
 use emu::vmstate::VmState;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+use unicorn::arm_const::RegisterARM;
+use unicorn::arm64_const::RegisterARM64;
+use unicorn::mips_const::RegisterMIPS;
+use unicorn::unicorn_const::MemHookType;
 
 pub struct EmuEffects<'a> {
     pub vmstate: &'a VmState,
     pub return_value: u64,
+    /// Second half of the returned value, for functions returning a pair.
+    pub return_high: Option<u64>,
+    /// Address of the returned struct, for functions returning one through a
+    /// hidden pointer.
+    pub returned: Option<u64>,
     pub args: PushableArgs,
+    /// Memory regions written by the call, outside of its own stack frame.
+    pub writes: Vec<MemWrite>,
+    /// Syscalls made by the call, in order.
+    pub syscalls: Vec<SyscallRecord>,
+    pub heap: HeapEffects,
+    /// Calls and returns taken during the call, if call tracing is enabled.
+    pub calls: Vec<CallEdge>,
+    /// Accesses out of the arguments laid out against guard pages.
+    pub out_of_bounds: Vec<OutOfBounds>,
+    /// Reads of the stack and arguments data not initialized by the call.
+    pub uninit_reads: Vec<UninitRead>,
+    /// Why the call stopped before returning, if it did.
+    pub fault: Option<Fault>,
+}
+
+impl<'a> EmuEffects<'a> {
+    /// Whether the call changed any byte in [addr, addr + size).
+    pub fn changed(&self, addr: u64, size: u64) -> bool {
+        return self.writes.iter().any(|w| w.changed(addr, size));
+    }
+
+    /// Data written by the call's syscalls to the file descriptor |fd|.
+    pub fn output(&self, fd: u64) -> Vec<u8> {
+        let mut output = Vec::new();
+        for syscall in &self.syscalls {
+            if syscall.output_fd == Some(fd) {
+                output.extend_from_slice(&syscall.data);
+            }
+        }
+        return output;
+    }
+
+    /// Floating point return value, for functions returning a double.
+    pub fn return_double(&self) -> Result<f64, Error> {
+        let engine = self.vmstate.engine.borrow();
+        let bits = match self.vmstate.arch {
+            Arch::X86 => try!(self.vmstate.st0()),
+            Arch::X86_64 => try!(self.vmstate.xmm(0)),
+            // Hard-float AAPCS returns doubles in d0, soft-float in r0:r1.
+            Arch::Arm if self.vmstate.object_info.hard_float => {
+                try!(engine.reg_read(RegisterARM::D0 as i32))
+            }
+            Arch::Arm => {
+                try!(engine.reg_read(RegisterARM::R0 as i32)) |
+                (try!(engine.reg_read(RegisterARM::R1 as i32)) << 32)
+            }
+            Arch::Arm64 => try!(engine.reg_read(RegisterARM64::D0 as i32)),
+            // Soft-float o32 returns doubles in v0:v1, in memory order.
+            Arch::Mips | Arch::Mipsel => {
+                let v0 = try!(engine.reg_read(RegisterMIPS::V0 as i32));
+                let v1 = try!(engine.reg_read(RegisterMIPS::V1 as i32));
+                match self.vmstate.arch.endian() {
+                    Endian::Little => v0 | (v1 << 32),
+                    Endian::Big => (v0 << 32) | v1,
+                }
+            }
+        };
+        return Ok(f64::from_bits(bits));
+    }
+
+    /// Floating point return value, for functions returning a float.
+    pub fn return_float(&self) -> Result<f32, Error> {
+        let bits = match self.vmstate.arch {
+            // x87 registers hold any precision.
+            Arch::X86 => return self.return_double().map(|v| v as f32),
+            Arch::X86_64 => try!(self.vmstate.xmm(0)),
+            // s0 is the low half of d0.
+            Arch::Arm if self.vmstate.object_info.hard_float => {
+                try!(self.vmstate
+                    .engine
+                    .borrow()
+                    .reg_read(RegisterARM::D0 as i32))
+            }
+            Arch::Arm | Arch::Mips | Arch::Mipsel => {
+                try!(self.vmstate.return_value())
+            }
+            Arch::Arm64 => {
+                try!(self.vmstate
+                    .engine
+                    .borrow()
+                    .reg_read(RegisterARM64::D0 as i32))
+            }
+        };
+        return Ok(f32::from_bits(bits as u32));
+    }
+
+    /// Number of calls made to the symbol |name|, at any depth.
+    pub fn call_count(&self, name: &str) -> usize {
+        return self.calls
+            .iter()
+            .filter(|c| {
+                c.kind == EdgeKind::Call &&
+                c.symbol.as_ref().map(|s| s.as_str()) == Some(name)
+            })
+            .count();
+    }
 }
 
 pub struct EmuEngine {
     pub vmstate: VmState,
     pub emu_counter: usize,
+    mem_tracker: MemTracker,
+    heap_tracker: HeapTracker,
+    call_tracer: CallTracer,
+    guard_pages: GuardPages,
+    limits: EmuLimits,
+    /// Address of the last invalid memory access of the current call.
+    invalid_access: Rc<Cell<Option<u64>>>,
 }
 
 impl EmuEngine {
@@ -26,9 +149,41 @@ impl EmuEngine {
             .expect("Failed to map code sentinel");
         try!(vmstate.init());
 
+        try!(vmstate.dirty_pages.shadow().attach(&vmstate.engine));
+        let mut mem_tracker = MemTracker::new(vmstate.engine.clone());
+        try!(mem_tracker.attach());
+        let mut heap_tracker = HeapTracker::new(vmstate.engine.clone(),
+                                                vmstate.arch,
+                                                vmstate.dirty_pages.clone());
+        try!(heap_tracker.attach(&vmstate.object_info));
+        let call_tracer = CallTracer::new(vmstate.engine.clone(), vmstate.arch);
+        let mut guard_pages = GuardPages::new(vmstate.engine.clone());
+        try!(guard_pages.attach());
+        // Only record the invalid accesses, the guard pages hook handles the
+        // expected ones.
+        let invalid_access = Rc::new(Cell::new(None));
+        {
+            let invalid_access = invalid_access.clone();
+            try!(vmstate.engine
+                .borrow_mut()
+                .add_mem_hook(MemHookType::MEM_INVALID,
+                              1,
+                              0,
+                              move |_, _, address, _, _| {
+                                  invalid_access.set(Some(address));
+                                  return false;
+                              }));
+        }
+
         return Ok(EmuEngine {
             vmstate: vmstate,
             emu_counter: 0,
+            mem_tracker: mem_tracker,
+            heap_tracker: heap_tracker,
+            call_tracer: call_tracer,
+            guard_pages: guard_pages,
+            limits: EmuLimits::default(),
+            invalid_access: invalid_access,
         });
     }
 
@@ -36,36 +191,133 @@ impl EmuEngine {
                 target: &TargetInfo,
                 args: &EmuArgs)
                 -> Result<EmuEffects, Error> {
+        let limits = self.limits;
+        return self.call_with_limits(target, args, limits);
+    }
+
+    /// Call the target function, giving up once it exceeds |limits| instead
+    /// of the engine's. A call stopped before returning still has effects,
+    /// along with its fault.
+    pub fn call_with_limits(&mut self,
+                            target: &TargetInfo,
+                            args: &EmuArgs,
+                            limits: EmuLimits)
+                            -> Result<EmuEffects, Error> {
         self.clean_state().expect("Cannot clean emulator state");
 
-        let pushable_args = try!(args.as_pushable(&self.vmstate));
+        let pushable_args = try!(args.as_pushable(&self.vmstate,
+                                                  limits.max_data));
+        self.vmstate.sync_emudata_map();
+        try!(self.guard_pages.guard(pushable_args.guarded(),
+                                    pushable_args.guard_pages()));
         let cc = ::emu::calling_convention::new(&target.cc);
-        try!(cc.init_args(&pushable_args.pushed_args(), &self.vmstate));
-        try!(self.call_and_return(target.fva));
+        let mut cc_args = pushable_args.pushed_args();
+        if let Some(sret) = pushable_args.sret() {
+            try!(cc.init_sret(sret, &mut cc_args, &self.vmstate));
+        }
+        try!(cc.init_args(&cc_args, &self.vmstate));
+        let fault = try!(self.call_and_return(target.fva, limits));
+        try!(self.heap_tracker.take_error());
 
-        return self.vmstate.collect_call_results(pushable_args);
+        let writes = try!(self.mem_tracker.writes(&self.vmstate.object_info));
+        let kernel = self.vmstate.kernel.as_ref().unwrap();
+        let growth = kernel.borrow().heap_growth();
+        let heap = self.heap_tracker.effects(growth);
+        let calls = self.call_tracer.edges(&self.vmstate.object_info);
+        let out_of_bounds = self.guard_pages.accesses();
+        return self.vmstate
+            .collect_call_results(pushable_args,
+                                  writes,
+                                  heap,
+                                  calls,
+                                  out_of_bounds,
+                                  fault);
+    }
+
+    pub fn limits(&self) -> EmuLimits {
+        return self.limits;
+    }
+
+    /// Set the limits of the subsequent calls.
+    pub fn set_limits(&mut self, limits: EmuLimits) {
+        self.limits = limits;
+    }
+
+    /// Record the calls and returns taken by the subsequent calls. Every
+    /// instruction is hooked while enabled, which slows the emulation down.
+    pub fn set_call_tracing(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled {
+            return self.call_tracer.attach();
+        }
+        return self.call_tracer.detach();
+    }
+
+    /// Track the allocations done through |allocator| at |addr|. Allocators
+    /// with a symbol are tracked from the start.
+    pub fn set_allocator(&mut self,
+                         addr: u64,
+                         allocator: Allocator)
+                         -> Result<(), Error> {
+        return self.heap_tracker.set_allocator(addr, allocator);
     }
 
     fn clean_state(&mut self) -> Result<(), Error> {
+        try!(self.guard_pages.reset());
         try!(self.vmstate.reset_stack());
-        try!(self.vmstate.reset_emudata());
         try!(self.vmstate.restore_snapshot());
+        try!(self.vmstate.reset_emudata());
         let kernel = self.vmstate.kernel.as_ref().unwrap();
         try!(kernel.borrow_mut().reset());
+        self.heap_tracker.reset();
+        self.call_tracer.reset();
+        self.vmstate.dirty_pages.shadow().reset();
+        self.invalid_access.set(None);
         return Ok(());
     }
 
-    fn call_and_return(&mut self, ip: u64) -> Result<(), Error> {
+    /// Run the function at |ip| until it returns to the code sentinel, and
+    /// tell why it didn't if so.
+    fn call_and_return(&mut self,
+                       ip: u64,
+                       limits: EmuLimits)
+                       -> Result<Option<Fault>, Error> {
         try!(self.vmstate.set_call_return(emu::CODE_SENTINEL));
+        if let Some(reg) = self.vmstate.arch.entry_reg() {
+            try!(self.vmstate.engine.borrow().reg_write(reg, ip));
+        }
+        // The function's own stack frame is scratch memory, only track the
+        // writes outside of it.
+        let stack_addr = self.vmstate.stack_info.as_ref().unwrap().addr;
+        self.mem_tracker.reset(stack_addr, try!(self.vmstate.sp()));
         self.emu_counter += 1;
-        return self.vmstate
+        let start = Instant::now();
+        let result = self.vmstate
             .engine
             .borrow()
             .emu_start(ip,
                        emu::CODE_SENTINEL,
-                       emu::EMU_TIMEOUT,
-                       emu::EMU_MAXCOUNT)
-            .map_err(|e| Error::ExecError(e));
+                       limits.timeout,
+                       limits.max_count);
+
+        let ip = self.vmstate.arch.code_addr(try!(self.vmstate.ip()));
+        if let Err(e) = result {
+            return Ok(Some(Fault::from_error(e,
+                                             ip,
+                                             self.invalid_access.get(),
+                                             &self.vmstate.object_info)));
+        }
+        // Unicorn stops silently on its limits, the function only returned
+        // if the sentinel was reached.
+        if ip != emu::CODE_SENTINEL {
+            let elapsed = start.elapsed();
+            let elapsed = elapsed.as_secs() * 1000 * 1000 +
+                          (elapsed.subsec_nanos() / 1000) as u64;
+            if limits.timeout > 0 && elapsed >= limits.timeout {
+                return Ok(Some(Fault::Timeout));
+            }
+            return Ok(Some(Fault::MaxCountReached));
+        }
+        return Ok(None);
     }
 
     pub fn emu_counter(&self) -> usize {
@@ -1,25 +1,29 @@
 use byteorder::{ByteOrder, LittleEndian};
 use emu;
 use emu::Error;
+use emu::arch::Arch;
+use emu::call_tracer::{EdgeKind, branch_kind};
 use emu::debugger::Debugger;
-use emu::env::{Env, Kernel};
+use emu::dirty_pages::DirtyPages;
+use emu::env::{Env, InitStrategy, Kernel, SyscallRecord};
 use emu::object_info::MemMap;
 use emu::vmstate::{DataWriter, VmState};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use rand::{Rng, StdRng};
 use std::rc::Rc;
 use unicorn::{Unicorn, uc_hook};
-use unicorn::unicorn_const::{PROT_READ, PROT_WRITE};
-use unicorn::{InsnSysX86, RegisterX86};
+use unicorn::unicorn_const::{CodeHookType, PROT_READ, PROT_WRITE};
+use unicorn::{InsnSysX86, RegisterARM, RegisterARM64, RegisterMIPS,
+              RegisterX86};
 
 enum Msr {
     FS = 0xC0000100, // GS = 0xC0000101,
 }
 
 pub fn init_state(vmstate: &mut VmState) -> Result<(), Error> {
-    // Set up the program stack as it would look from the kernel and
-    // emulate <__start> up to <main>. This should give us a nice
-    // initialized program state, if it worked...
+    // Set up the program stack as it would look from the kernel, then
+    // emulate the program's own initialization as its strategy says. This
+    // should give us a nice initialized program state, if it worked...
 
     // TODO
     let kernel_map_addr = try!(vmstate.mem_map(MemMap {
@@ -28,27 +32,282 @@ pub fn init_state(vmstate: &mut VmState) -> Result<(), Error> {
         flags: PROT_READ | PROT_WRITE,
         name: String::from("[kernel]"),
     }));
-    let mut kernel_writer = DataWriter::new(vmstate, kernel_map_addr);
+    let mut kernel_writer =
+        DataWriter::new(vmstate,
+                        kernel_map_addr,
+                        kernel_map_addr + emu::KERNEL_SIZE as u64);
 
     try!(init_stack(vmstate, &mut kernel_writer));
+    match vmstate.arch {
+        Arch::X86 => try!(init_gdt(vmstate)),
+        Arch::Arm | Arch::Arm64 => try!(init_fpu(vmstate)),
+        Arch::X86_64 | Arch::Mips | Arch::Mipsel => {}
+    }
+
+    let entry = vmstate.object_info.entry;
+    return match vmstate.init_strategy {
+        InitStrategy::Main => run_until_main(vmstate),
+        InitStrategy::Until(addr) => run_until(vmstate, entry, addr),
+        InitStrategy::UntilSymbol(ref name) => {
+            let addr = try!(symbol_addr(vmstate, name));
+            run_until(vmstate, entry, addr)
+        }
+        InitStrategy::None => init_tls(vmstate, &mut kernel_writer),
+    };
+}
 
-    // Emulate up to main.
-    let start_fva = vmstate.object_info
-        .symbols
-        .get("_start")
-        .expect("_start not found")
-        .value;
-    let main_fva =
-        vmstate.object_info.symbols.get("main").expect("main not found").value;
+fn symbol_addr(vmstate: &VmState, name: &str) -> Result<u64, Error> {
+    return match vmstate.object_info.symbols.get(name) {
+        Some(symbol) if symbol.value != 0 => Ok(symbol.value),
+        _ => Err(Error::SymbolNotFound(String::from(name))),
+    };
+}
+
+/// Emulate from |start| up to |stop|, failing if |stop| isn't reached
+/// within the engine's limits.
+fn run_until(vmstate: &VmState, start: u64, stop: u64) -> Result<(), Error> {
+    // Execution stops on the code address, without the Thumb bit.
+    let stop = vmstate.arch.code_addr(stop);
 
-    // let mut debugger = Debugger::new(vmstate.engine.clone());
+    // let mut debugger = Debugger::new(vmstate.engine.clone(), vmstate.arch);
     // debugger.attach().expect("Failed to attach debugger");
-    vmstate.engine
+    try!(vmstate.engine
         .borrow()
-        .emu_start(start_fva, main_fva, emu::EMU_TIMEOUT, emu::EMU_MAXCOUNT)
-        .expect("Failed to run up to main");
+        .emu_start(start, stop, emu::EMU_TIMEOUT, emu::EMU_MAXCOUNT)
+        .map_err(|e| Error::InitError(Some(e))));
     // debugger.detach().expect("Failed to detach debugger");
 
+    if vmstate.arch.code_addr(try!(vmstate.ip())) != stop {
+        return Err(Error::InitError(None));
+    }
+    return Ok(());
+}
+
+/// Emulate from the entry point up to main. Without its symbol, main is
+/// found as the first argument of the first call leaving the entry point,
+/// the call to the libc start function, and the emulation goes on up to
+/// its call.
+fn run_until_main(vmstate: &VmState) -> Result<(), Error> {
+    let entry = vmstate.object_info.entry;
+    if let Ok(main) = symbol_addr(vmstate, "main") {
+        return run_until(vmstate, entry, main);
+    }
+
+    let start_main = try!(run_until_first_call(vmstate, entry));
+    let main = try!(first_arg(vmstate));
+    return run_until(vmstate, start_main, main);
+}
+
+/// Emulate from the entry point |start| up to the entry of the first function
+/// it calls, and return the address of that function. Calls to the next
+/// instruction or within the entry function, such as the ones getting the
+/// program counter in position independent code, are not leaving it and
+/// are skipped.
+fn run_until_first_call(vmstate: &VmState, start: u64) -> Result<u64, Error> {
+    let arch = vmstate.arch;
+    let object_info = &vmstate.object_info;
+    let entry = arch.code_addr(start);
+    let entry_end = match object_info.function_at(arch, entry)
+        .and_then(|name| object_info.symbols.get(name)) {
+        Some(symbol) => entry + symbol.size,
+        None => entry,
+    };
+    // Return address of the call just executed, whose callee is entered on
+    // the next instruction, after the delay slot on MIPS.
+    let pending: Rc<Cell<Option<(u64, usize)>>> = Rc::new(Cell::new(None));
+    let callee = Rc::new(Cell::new(None));
+    let hook = {
+        let pending = pending.clone();
+        let callee = callee.clone();
+        try!(vmstate.engine
+            .borrow_mut()
+            .add_code_hook(CodeHookType::CODE,
+                           1,
+                           0,
+                           move |engine, address, size| {
+                if callee.get().is_some() {
+                    return;
+                }
+                match pending.get() {
+                    Some((ret, 1)) => {
+                        pending.set(None);
+                        if address != ret &&
+                           (address < entry || address >= entry_end) {
+                            // Resuming needs the Thumb bit of Thumb callees.
+                            let thumb = arch.in_thumb(engine) as u64;
+                            callee.set(Some(address | thumb));
+                            let _ = engine.emu_stop();
+                            return;
+                        }
+                    }
+                    Some((ret, left)) => {
+                        pending.set(Some((ret, left - 1)));
+                        return;
+                    }
+                    None => {}
+                }
+                let kind = match engine.mem_read(address, size as usize) {
+                    Ok(code) => branch_kind(engine, arch, &code),
+                    Err(_) => None,
+                };
+                if kind == Some(EdgeKind::Call) {
+                    pending.set(Some(match arch {
+                        Arch::Mips | Arch::Mipsel => (address + 8, 2),
+                        _ => (address + size as u64, 1),
+                    }));
+                }
+            }))
+    };
+    let r = vmstate.engine
+        .borrow()
+        .emu_start(start, 0, emu::EMU_TIMEOUT, emu::EMU_MAXCOUNT)
+        .map_err(|e| Error::InitError(Some(e)));
+    try!(vmstate.engine.borrow_mut().remove_hook(hook));
+    try!(r);
+
+    return match callee.get() {
+        Some(addr) => Ok(addr),
+        None => Err(Error::InitError(None)),
+    };
+}
+
+/// First argument of the function just entered, in the default calling
+/// convention of the architecture.
+fn first_arg(vmstate: &VmState) -> Result<u64, Error> {
+    let reg = match vmstate.arch {
+        Arch::X86 => {
+            // Past the return address.
+            let sp = try!(vmstate.sp());
+            return vmstate.read_usize(sp + 4);
+        }
+        Arch::X86_64 => RegisterX86::RDI as i32,
+        Arch::Arm => RegisterARM::R0 as i32,
+        Arch::Arm64 => RegisterARM64::X0 as i32,
+        Arch::Mips | Arch::Mipsel => RegisterMIPS::A0 as i32,
+    };
+    return Ok(try!(vmstate.engine.borrow().reg_read(reg)));
+}
+
+/// Thread pointer of the main thread, in the middle of the TLS mapping so
+/// the TLS blocks fit on either side.
+const THREAD_POINTER: u64 = emu::TLS_ADDR + emu::TLS_SIZE as u64 / 2;
+/// Offset of the thread pointer past the TLS block, on MIPS.
+const MIPS_TP_OFFSET: u64 = 0x7000;
+
+/// Set up the thread-local storage of the main thread from the program's
+/// template, as libc would during its initialization.
+fn init_tls(vmstate: &VmState,
+            kernel_writer: &mut DataWriter)
+            -> Result<(), Error> {
+    try!(vmstate.mem_map(MemMap {
+        addr: emu::TLS_ADDR,
+        size: emu::TLS_SIZE,
+        flags: PROT_READ | PROT_WRITE,
+        name: String::from("[tls]"),
+    }));
+
+    let ptr_size = vmstate.arch.pointer_size() as u64;
+    let tp = THREAD_POINTER;
+    if let Some(ref tls) = vmstate.object_info.tls {
+        let size = (tls.mem_size as u64 + tls.align - 1) & !(tls.align - 1);
+        // The block is below the thread pointer on x86, past the thread
+        // control block elsewhere.
+        let block = match vmstate.arch {
+            Arch::X86 | Arch::X86_64 => tp - size,
+            Arch::Arm | Arch::Arm64 => {
+                (tp + 2 * ptr_size + tls.align - 1) & !(tls.align - 1)
+            }
+            Arch::Mips | Arch::Mipsel => tp,
+        };
+        let image = try!(vmstate.engine
+            .borrow()
+            .mem_read(tls.addr, tls.file_size));
+        try!(vmstate.mem_write(block, &image));
+    }
+
+    let engine = vmstate.engine.borrow();
+    match vmstate.arch {
+        Arch::X86 => {
+            // The thread control block points to itself.
+            try!(vmstate.mem_write(tp, &vmstate.arch.pack(tp)));
+            // user_desc of a flat 32 bits segment based at tp, installed in
+            // the first TLS entry and loaded in gs.
+            let mut desc = [0; 16];
+            LittleEndian::write_u32(&mut desc[0..4], GDT_ENTRY_TLS_MIN as u32);
+            LittleEndian::write_u32(&mut desc[4..8], tp as u32);
+            LittleEndian::write_u32(&mut desc[8..12], 0xfffff);
+            LittleEndian::write_u32(&mut desc[12..16], 0x51);
+            let desc_addr = try!(kernel_writer.write_data(&desc));
+            try!(set_thread_area(&engine, &vmstate.dirty_pages, desc_addr));
+            drop(engine);
+            // mov ax, selector; mov gs, ax
+            let selector = (GDT_ENTRY_TLS_MIN << 3 | 3) as u8;
+            return vmstate.run_shellcode(&[0x66, 0xB8, selector, 0x00,
+                                           0x8E, 0xE8]);
+        }
+        Arch::X86_64 => {
+            try!(vmstate.mem_write(tp, &vmstate.arch.pack(tp)));
+            drop(engine);
+            // mov ecx, FS; mov eax, tp_low; mov edx, tp_high; wrmsr
+            let mut shellcode = vec![0xB9, 0, 0, 0, 0, 0xB8, 0, 0, 0, 0, 0xBA,
+                                     0, 0, 0, 0, 0x0F, 0x30];
+            LittleEndian::write_u32(&mut shellcode[1..5], Msr::FS as u32);
+            LittleEndian::write_u32(&mut shellcode[6..10], tp as u32);
+            LittleEndian::write_u32(&mut shellcode[11..15],
+                                    (tp >> 32) as u32);
+            return vmstate.run_shellcode(&shellcode);
+        }
+        Arch::Arm => try!(engine.reg_write(RegisterARM::C13_C0_3 as i32, tp)),
+        Arch::Arm64 => {
+            try!(engine.reg_write(RegisterARM64::TPIDR_EL0 as i32, tp))
+        }
+        Arch::Mips | Arch::Mipsel => {
+            try!(engine.reg_write(RegisterMIPS::CP0_USERLOCAL as i32,
+                                  tp + MIPS_TP_OFFSET))
+        }
+    }
+    return Ok(());
+}
+
+/// Global descriptor table holding the TLS segments set by set_thread_area.
+const GDT_ADDR: u64 = emu::KERNEL_ADDR + 0x8000;
+const GDT_ENTRIES: u64 = 16;
+/// First and last GDT entries available to set_thread_area.
+const GDT_ENTRY_TLS_MIN: u64 = 6;
+const GDT_ENTRY_TLS_MAX: u64 = 8;
+
+/// Load an empty GDT, on x86. The engine starts without one, so the
+/// segments set by set_thread_area could not be loaded.
+fn init_gdt(vmstate: &VmState) -> Result<(), Error> {
+    let gdtr_addr = GDT_ADDR - 8;
+    let mut gdtr = [0; 6];
+    LittleEndian::write_u16(&mut gdtr, (GDT_ENTRIES * 8 - 1) as u16);
+    LittleEndian::write_u32(&mut gdtr[2..], GDT_ADDR as u32);
+    try!(vmstate.mem_write(gdtr_addr, &gdtr));
+
+    // lgdt [gdtr_addr]
+    let mut shellcode = vec![0x0F, 0x01, 0x15, 0, 0, 0, 0];
+    LittleEndian::write_u32(&mut shellcode[3..], gdtr_addr as u32);
+    return vmstate.run_shellcode(&shellcode);
+}
+
+/// Enable the floating point unit, on ARM. The engine starts with it
+/// disabled.
+fn init_fpu(vmstate: &VmState) -> Result<(), Error> {
+    let engine = vmstate.engine.borrow();
+    match vmstate.arch {
+        Arch::Arm => {
+            // Full access to cp10 and cp11, then set FPEXC.EN.
+            let cpacr = try!(engine.reg_read(RegisterARM::C1_C0_2 as i32));
+            try!(engine.reg_write(RegisterARM::C1_C0_2 as i32,
+                                  cpacr | (0xf << 20)));
+            try!(engine.reg_write(RegisterARM::FPEXC as i32, 0x40000000));
+        }
+        Arch::Arm64 => {
+            try!(engine.reg_write(RegisterARM64::CPACR_EL1 as i32, 3 << 20));
+        }
+        _ => {}
+    }
     return Ok(());
 }
 
@@ -123,21 +382,161 @@ fn init_stack(vmstate: &VmState,
 }
 
 pub struct LinuxKernel {
+    arch: Arch,
     intr_hook: Option<uc_hook>,
     brk_ptr: u64,
+    /// End of the anonymous mappings given by mmap.
+    mmap_ptr: u64,
+    /// Pages written by the syscalls must be restored between calls.
+    dirty_pages: DirtyPages,
+    syscalls: Vec<SyscallRecord>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
+/// -EFAULT, returned when a syscall is given an invalid buffer.
+const EFAULT: u64 = 0xfffffffffffffff2;
+/// -ENOMEM, returned when mmap runs out of memory.
+const ENOMEM: u64 = 0xfffffffffffffff4;
+/// -ENODEV, returned when mmap is asked to map a file.
+const ENODEV: u64 = 0xffffffffffffffed;
+/// -EINVAL, returned when a syscall is given invalid arguments.
+const EINVAL: u64 = 0xffffffffffffffea;
+/// -ESRCH, returned when set_thread_area runs out of TLS entries.
+const ESRCH: u64 = 0xfffffffffffffffd;
+/// Maximum number of buffers given to writev.
+const IOV_MAX: u64 = 1024;
+/// Maximum size of the data written by a syscall. The size is given by the
+/// emulated code, which can be any garbage for a wrong candidate.
+const MAX_IO_SIZE: usize = emu::EMUDATA_MAX_SIZE;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MIPS_MAP_ANONYMOUS: u64 = 0x800;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Syscall {
-    Open = 2,
-    Mmap = 9,
-    Brk = 12,
-    Writev = 20,
-    Uname = 63,
-    Prctl = 158,
+    Write,
+    Open,
+    Openat,
+    Mmap,
+    /// mmap with the offset in pages, on 32 bits architectures.
+    Mmap2,
+    Munmap,
+    Brk,
+    Writev,
+    Uname,
+    Prctl,
+    SetThreadArea,
+    /// Set the TLS register, ARM's private set_tls and MIPS' set_thread_area.
+    SetTls,
+}
+
+impl Syscall {
+    /// Returns the syscall numbered |number| on |arch|.
+    fn from_number(arch: Arch, number: u64) -> Option<Syscall> {
+        let syscall = match arch {
+            Arch::X86_64 => {
+                match number {
+                    1 => Syscall::Write,
+                    2 => Syscall::Open,
+                    9 => Syscall::Mmap,
+                    11 => Syscall::Munmap,
+                    12 => Syscall::Brk,
+                    20 => Syscall::Writev,
+                    63 => Syscall::Uname,
+                    158 => Syscall::Prctl,
+                    257 => Syscall::Openat,
+                    _ => return None,
+                }
+            }
+            // The ARM EABI numbers follow the x86 ones.
+            Arch::X86 | Arch::Arm => {
+                match number {
+                    4 => Syscall::Write,
+                    5 => Syscall::Open,
+                    45 => Syscall::Brk,
+                    91 => Syscall::Munmap,
+                    122 => Syscall::Uname,
+                    146 => Syscall::Writev,
+                    192 => Syscall::Mmap2,
+                    243 if arch == Arch::X86 => Syscall::SetThreadArea,
+                    295 if arch == Arch::X86 => Syscall::Openat,
+                    322 if arch == Arch::Arm => Syscall::Openat,
+                    0xf0005 if arch == Arch::Arm => Syscall::SetTls,
+                    _ => return None,
+                }
+            }
+            // o32 numbers start at 4000.
+            Arch::Mips | Arch::Mipsel => {
+                match number {
+                    4004 => Syscall::Write,
+                    4005 => Syscall::Open,
+                    4045 => Syscall::Brk,
+                    4090 => Syscall::Mmap,
+                    4091 => Syscall::Munmap,
+                    4122 => Syscall::Uname,
+                    4146 => Syscall::Writev,
+                    4210 => Syscall::Mmap2,
+                    4283 => Syscall::SetTls,
+                    4288 => Syscall::Openat,
+                    _ => return None,
+                }
+            }
+            Arch::Arm64 => {
+                match number {
+                    56 => Syscall::Openat,
+                    64 => Syscall::Write,
+                    66 => Syscall::Writev,
+                    160 => Syscall::Uname,
+                    214 => Syscall::Brk,
+                    215 => Syscall::Munmap,
+                    222 => Syscall::Mmap,
+                    _ => return None,
+                }
+            }
+        };
+        return Some(syscall);
+    }
 }
 
+/// Registers holding the syscall number and arguments.
+const X86_64_SYSCALL_REGS: [i32; 7] = [RegisterX86::RAX as i32,
+                                       RegisterX86::RDI as i32,
+                                       RegisterX86::RSI as i32,
+                                       RegisterX86::RDX as i32,
+                                       RegisterX86::R10 as i32,
+                                       RegisterX86::R8 as i32,
+                                       RegisterX86::R9 as i32];
+const X86_SYSCALL_REGS: [i32; 7] = [RegisterX86::EAX as i32,
+                                    RegisterX86::EBX as i32,
+                                    RegisterX86::ECX as i32,
+                                    RegisterX86::EDX as i32,
+                                    RegisterX86::ESI as i32,
+                                    RegisterX86::EDI as i32,
+                                    RegisterX86::EBP as i32];
+const ARM_SYSCALL_REGS: [i32; 7] = [RegisterARM::R7 as i32,
+                                    RegisterARM::R0 as i32,
+                                    RegisterARM::R1 as i32,
+                                    RegisterARM::R2 as i32,
+                                    RegisterARM::R3 as i32,
+                                    RegisterARM::R4 as i32,
+                                    RegisterARM::R5 as i32];
+const ARM64_SYSCALL_REGS: [i32; 7] = [RegisterARM64::X8 as i32,
+                                      RegisterARM64::X0 as i32,
+                                      RegisterARM64::X1 as i32,
+                                      RegisterARM64::X2 as i32,
+                                      RegisterARM64::X3 as i32,
+                                      RegisterARM64::X4 as i32,
+                                      RegisterARM64::X5 as i32];
+/// The fifth and sixth arguments are on the stack, after the space reserved
+/// for the first four.
+const MIPS_SYSCALL_REGS: [i32; 5] = [RegisterMIPS::V0 as i32,
+                                     RegisterMIPS::A0 as i32,
+                                     RegisterMIPS::A1 as i32,
+                                     RegisterMIPS::A2 as i32,
+                                     RegisterMIPS::A3 as i32];
+/// Interrupt raised by svc on ARM.
+const EXCP_SWI: u32 = 2;
+/// Interrupt raised by syscall on MIPS.
+const EXCP_SYSCALL: u32 = 17;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 enum PrctlCode {
@@ -165,12 +564,99 @@ fn read_str(engine: &Unicorn, addr: u64) -> Result<String, Error> {
     return String::from_utf8(data_buf).map_err(|e| Error::FromUtf8Error(e));
 }
 
-fn read_usize(engine: &Unicorn, addr: u64) -> Result<u64, Error> {
-    // TODO: Make it arch independant.
-    return Ok(LittleEndian::read_u64(&try!(engine.mem_read(addr, 8))));
+fn read_usize(engine: &Unicorn, arch: Arch, addr: u64) -> Result<u64, Error> {
+    let data = try!(engine.mem_read(addr, arch.pointer_size()));
+    return Ok(arch.unpack(&data));
 }
 
-fn set_fs(engine: &Unicorn, fs: u64, rip: u64) -> Result<(), Error> {
+/// Read the buffers of the |count| iovec structures at |iov|, one after the
+/// other. Fails with the syscall's error code.
+fn read_iovec(engine: &Unicorn,
+              arch: Arch,
+              iov: u64,
+              count: u64)
+              -> Result<Vec<u8>, u64> {
+    if count > IOV_MAX {
+        return Err(EINVAL);
+    }
+    let ptr_size = arch.pointer_size() as u64;
+    let mut data = Vec::new();
+    for i in 0..count {
+        let entry = iov + 2 * ptr_size * i;
+        let base = try!(read_usize(engine, arch, entry).map_err(|_| EFAULT));
+        let len = try!(read_usize(engine, arch, entry + ptr_size)
+            .map_err(|_| EFAULT));
+        if len > (MAX_IO_SIZE - data.len()) as u64 {
+            return Err(EINVAL);
+        }
+        data.extend(try!(engine.mem_read(base, len as usize)
+            .map_err(|_| EFAULT)));
+    }
+    return Ok(data);
+}
+
+/// Install the TLS segment described by the user_desc structure at |addr| in
+/// the GDT. Returns the syscall result.
+fn set_thread_area(engine: &Unicorn,
+                   dirty_pages: &DirtyPages,
+                   addr: u64)
+                   -> Result<u64, Error> {
+    let desc = try!(engine.mem_read(addr, 16));
+    let mut entry = LittleEndian::read_u32(&desc[0..4]) as u64;
+    let base = LittleEndian::read_u32(&desc[4..8]) as u64;
+    let limit = LittleEndian::read_u32(&desc[8..12]) as u64;
+    let flags = LittleEndian::read_u32(&desc[12..16]) as u64;
+
+    if entry == 0xffffffff {
+        // Find a free entry and give it back to the caller.
+        entry = GDT_ENTRY_TLS_MIN;
+        while entry <= GDT_ENTRY_TLS_MAX {
+            let used = try!(engine.mem_read(GDT_ADDR + entry * 8, 8));
+            if used.iter().all(|&b| b == 0) {
+                break;
+            }
+            entry += 1;
+        }
+        if entry > GDT_ENTRY_TLS_MAX {
+            return Ok(ESRCH);
+        }
+        let mut number = [0; 4];
+        LittleEndian::write_u32(&mut number, entry as u32);
+        try!(engine.mem_write(addr, &number));
+        dirty_pages.mark(addr, number.len());
+    } else if entry < GDT_ENTRY_TLS_MIN || entry > GDT_ENTRY_TLS_MAX {
+        return Ok(EINVAL);
+    }
+
+    let seg_32bit = flags & 1;
+    let contents = (flags >> 1) & 3;
+    let read_exec_only = (flags >> 3) & 1;
+    let limit_in_pages = (flags >> 4) & 1;
+    let seg_not_present = (flags >> 5) & 1;
+    let useable = (flags >> 6) & 1;
+
+    // Present, DPL 3, code or data segment.
+    let access = ((seg_not_present ^ 1) << 7) | (3 << 5) | (1 << 4) |
+                 (contents << 2) | ((read_exec_only ^ 1) << 1);
+    let granularity = (limit_in_pages << 3) | (seg_32bit << 2) | useable;
+    let descriptor = (limit & 0xffff) | ((base & 0xffffff) << 16) |
+                     (access << 40) |
+                     (((limit >> 16) & 0xf) << 48) |
+                     (granularity << 52) |
+                     (((base >> 24) & 0xff) << 56);
+    let mut data = [0; 8];
+    LittleEndian::write_u64(&mut data, descriptor);
+    try!(engine.mem_write(GDT_ADDR + entry * 8, &data));
+    dirty_pages.mark(GDT_ADDR + entry * 8, data.len());
+    return Ok(0);
+}
+
+/// Set fs through its MSR, on x86_64.
+fn set_fs(engine: &Unicorn,
+          dirty_pages: &DirtyPages,
+          fs: u64,
+          rip: u64)
+          -> Result<(), Error> {
     let saved_rax = engine.reg_read(RegisterX86::RAX as i32).unwrap();
     let saved_rcx = engine.reg_read(RegisterX86::RCX as i32).unwrap();
     let saved_rdx = engine.reg_read(RegisterX86::RDX as i32).unwrap();
@@ -182,6 +668,7 @@ fn set_fs(engine: &Unicorn, fs: u64, rip: u64) -> Result<(), Error> {
     buf.resize(8, 0);
     LittleEndian::write_u64(&mut buf, rip);
     try!(engine.mem_write(sp - 8, &buf));
+    dirty_pages.mark(sp - 8, buf.len());
 
 
     // println!("Setting FS as {:x}", fs);
@@ -208,14 +695,18 @@ fn set_fs(engine: &Unicorn, fs: u64, rip: u64) -> Result<(), Error> {
     // ret
     shellcode.push(0xC3);
 
-    return run_shellcode(engine, &shellcode);
+    return run_shellcode(engine, dirty_pages, &shellcode);
 }
 
-fn run_shellcode(engine: &Unicorn, code: &[u8]) -> Result<(), Error> {
+fn run_shellcode(engine: &Unicorn,
+                 dirty_pages: &DirtyPages,
+                 code: &[u8])
+                 -> Result<(), Error> {
     // Dirty hack to hijack control flow...
     let addr = emu::SHELLCODE_ADDR;
 
     try!(engine.mem_write(addr, code));
+    dirty_pages.mark(addr, code.len());
     try!(engine.reg_write(RegisterX86::RIP as i32, addr - 2));
 
     // println!("shellcode: {:?}", engine.mem_read(addr, code.len()));
@@ -227,44 +718,114 @@ fn run_shellcode(engine: &Unicorn, code: &[u8]) -> Result<(), Error> {
 
 impl LinuxKernel {
     pub fn on_syscall(&mut self, engine: &Unicorn) {
-        let rip = engine.reg_read(RegisterX86::RIP as i32).unwrap();
-        let sysno = engine.reg_read(RegisterX86::RAX as i32).unwrap();
+        let rip = engine.reg_read(self.arch.ip_reg()).unwrap();
+        let regs: &[i32] = match self.arch {
+            Arch::X86 => &X86_SYSCALL_REGS,
+            Arch::X86_64 => &X86_64_SYSCALL_REGS,
+            Arch::Arm => &ARM_SYSCALL_REGS,
+            Arch::Arm64 => &ARM64_SYSCALL_REGS,
+            Arch::Mips | Arch::Mipsel => &MIPS_SYSCALL_REGS,
+        };
+        // The result goes in the first argument's register on ARM.
+        let result_reg = match self.arch {
+            Arch::Arm | Arch::Arm64 => regs[1],
+            _ => regs[0],
+        };
+        let sysno = engine.reg_read(regs[0]).unwrap();
         // println!("syscall({}) at {:x}", sysno, rip);
 
-        let argv = vec![
-            engine.reg_read(RegisterX86::RDI as i32).unwrap(),
-            engine.reg_read(RegisterX86::RSI as i32).unwrap(),
-            engine.reg_read(RegisterX86::RDX as i32).unwrap(),
-            engine.reg_read(RegisterX86::R10 as i32).unwrap(),
-            engine.reg_read(RegisterX86::R8 as i32).unwrap(),
-            engine.reg_read(RegisterX86::R9 as i32).unwrap(),
-        ];
-
-        let result = match sysno {
-            n if n == Syscall::Open as u64 => {
-                // println!("Open(\"{}\")", read_str(engine, argv[0]).unwrap());
+        let mut argv: Vec<u64> = regs[1..]
+            .iter()
+            .map(|&reg| engine.reg_read(reg).unwrap())
+            .collect();
+        if argv.len() < 6 {
+            let sp = engine.reg_read(self.arch.sp_reg()).unwrap();
+            for i in argv.len()..6 {
+                let addr = sp + (i * self.arch.pointer_size()) as u64;
+                argv.push(read_usize(engine, self.arch, addr).unwrap_or(0));
+            }
+        }
+
+        let mut record = SyscallRecord {
+            number: sysno,
+            args: argv.clone(),
+            data: Vec::new(),
+            output_fd: None,
+            result: 0,
+        };
+
+        let result = match Syscall::from_number(self.arch, sysno) {
+            Some(Syscall::Write) if argv[2] > MAX_IO_SIZE as u64 => EINVAL,
+            Some(Syscall::Write) => {
+                match engine.mem_read(argv[1], argv[2] as usize) {
+                    Ok(data) => {
+                        record.data = data;
+                        record.output_fd = Some(argv[0]);
+                        argv[2]
+                    }
+                    Err(_) => EFAULT,
+                }
+            }
+            Some(Syscall::Open) => {
+                record.data = read_str(engine, argv[0])
+                    .map(|path| path.into_bytes())
+                    .unwrap_or(Vec::new());
+                0xffffffffffffffff
+            }
+            Some(Syscall::Openat) => {
+                record.data = read_str(engine, argv[1])
+                    .map(|path| path.into_bytes())
+                    .unwrap_or(Vec::new());
                 0xffffffffffffffff
             }
-            n if n == Syscall::Brk as u64 => {
+            Some(Syscall::Brk) => {
                 // println!("Brk(0x{:x})", argv[0]);
                 let ptr = argv[0];
-                if ptr == 0 {
+                let brk_end = emu::BRK_ADDR + emu::BRK_SIZE as u64;
+                if ptr < emu::BRK_ADDR || ptr > brk_end {
+                    // Failures return the current break.
                     self.brk_ptr
                 } else {
                     self.brk_ptr = ptr;
                     ptr
                 }
             }
-            n if n == Syscall::Writev as u64 => {
-                /*for n in 0..argv[2] {
-                    let addr = read_usize(engine, argv[1] + 0x10 * n);
-                    let size = read_usize(engine, argv[1] + 8 + 0x10 * n);
-                    let data = engine.mem_read(addr, size as usize);
-                    // println!("{:?} > 2", String::from_utf8_lossy(&data));
-                }*/
-                0xffffffffffffffff
+            Some(Syscall::Mmap) |
+            Some(Syscall::Mmap2) => {
+                // Only anonymous mappings are supported, they are given from
+                // the [mmap] region and never reused.
+                // The size is the guest's, any garbage must fail cleanly.
+                let end = argv[1]
+                    .checked_add(0xfff)
+                    .map(|size| size & !0xfff)
+                    .and_then(|size| self.mmap_ptr.checked_add(size));
+                let mmap_end = emu::MMAP_ADDR + emu::MMAP_SIZE as u64;
+                let map_anonymous = match self.arch {
+                    Arch::Mips | Arch::Mipsel => MIPS_MAP_ANONYMOUS,
+                    _ => MAP_ANONYMOUS,
+                };
+                match end {
+                    _ if argv[3] & map_anonymous == 0 => ENODEV,
+                    Some(end) if end > self.mmap_ptr && end <= mmap_end => {
+                        let ptr = self.mmap_ptr;
+                        self.mmap_ptr = end;
+                        ptr
+                    }
+                    _ => ENOMEM,
+                }
+            }
+            Some(Syscall::Writev) => {
+                match read_iovec(engine, self.arch, argv[1], argv[2]) {
+                    Ok(data) => {
+                        let len = data.len() as u64;
+                        record.data = data;
+                        record.output_fd = Some(argv[0]);
+                        len
+                    }
+                    Err(errno) => errno,
+                }
             }
-            n if n == Syscall::Uname as u64 => {
+            Some(Syscall::Uname) => {
                 fn extend_64_bytes(data: &[u8]) -> Vec<u8> {
                     let mut bytes = Vec::from(data);
                     bytes.resize(64, 0);
@@ -279,13 +840,21 @@ impl LinuxKernel {
                 uname.append(&mut extend_64_bytes("#1 SMP PREEMPT Wed Jun 8 \
                                                    08:40:59 CEST 2016"
                     .as_bytes()));
-                uname.append(&mut extend_64_bytes("x86_64".as_bytes()));
+                let machine = match self.arch {
+                    Arch::X86 => "i686",
+                    Arch::X86_64 => "x86_64",
+                    Arch::Arm => "armv7l",
+                    Arch::Arm64 => "aarch64",
+                    Arch::Mips | Arch::Mipsel => "mips",
+                };
+                uname.append(&mut extend_64_bytes(machine.as_bytes()));
                 uname.append(&mut extend_64_bytes("GNU/Linux".as_bytes()));
                 engine.mem_write(argv[0], &uname)
                     .expect("Failed to write uname data");
+                self.dirty_pages.mark(argv[0], uname.len());
                 0
             }
-            n if n == Syscall::Prctl as u64 => {
+            Some(Syscall::Prctl) => {
                 let code = argv[0];
                 let addr = argv[1];
                 // println!("prctl(0x{:x}, 0x{:x})", argv[0], argv[1]);
@@ -294,18 +863,44 @@ impl LinuxKernel {
                         let next_rip = rip + 2;
                         engine.reg_write(RegisterX86::RAX as i32, 0)
                             .expect("Failed to set rax");
-                        set_fs(engine, addr, next_rip)
+                        set_fs(engine, &self.dirty_pages, addr, next_rip)
                             .expect("Failed to set fs");
+                        self.syscalls.push(record);
                         return;
                     }
                     _ => panic!("Prctl code not implemented"),
                 };
             }
+            Some(Syscall::SetThreadArea) => {
+                set_thread_area(engine, &self.dirty_pages, argv[0])
+                    .unwrap_or(EFAULT)
+            }
+            Some(Syscall::SetTls) => {
+                let tls_reg = match self.arch {
+                    Arch::Arm => RegisterARM::C13_C0_3 as i32,
+                    _ => RegisterMIPS::CP0_USERLOCAL as i32,
+                };
+                engine.reg_write(tls_reg, argv[0])
+                    .expect("Failed to set tls");
+                0
+            }
             _ => 0,
         };
 
-        engine.reg_write(RegisterX86::RAX as i32, result)
-            .expect("Failed to set rax");
+        record.result = result;
+        self.syscalls.push(record);
+        if self.arch == Arch::Mips || self.arch == Arch::Mipsel {
+            // Errors are returned as a positive errno with a3 set.
+            let failed = result > !0xfff;
+            let result = if failed { result.wrapping_neg() } else { result };
+            engine.reg_write(RegisterMIPS::A3 as i32, failed as u64)
+                .expect("Failed to set syscall error flag");
+            engine.reg_write(result_reg, result)
+                .expect("Failed to set syscall result");
+            return;
+        }
+        engine.reg_write(result_reg, result)
+            .expect("Failed to set syscall result");
     }
 }
 
@@ -315,8 +910,12 @@ pub struct LinuxEnv {
 impl Env for LinuxEnv {
     fn attach(&self, vmstate: &mut VmState) -> Rc<RefCell<Kernel>> {
         let kernel = Rc::new(RefCell::new(LinuxKernel {
+            arch: vmstate.arch,
             intr_hook: None,
             brk_ptr: emu::BRK_ADDR,
+            mmap_ptr: emu::MMAP_ADDR,
+            dirty_pages: vmstate.dirty_pages.clone(),
+            syscalls: Vec::new(),
         }));
 
         vmstate.mem_map(MemMap {
@@ -327,14 +926,55 @@ impl Env for LinuxEnv {
             })
             .expect("Failed to map heap");
 
-        let hook_kernel = kernel.clone();
-        kernel.borrow_mut().intr_hook = Some(vmstate.engine
-            .borrow_mut()
-            .add_insn_sys_hook(InsnSysX86::SYSCALL, 1, 0, move |engine| {
-                hook_kernel.borrow_mut()
-                    .on_syscall(engine);
+        vmstate.mem_map(MemMap {
+                addr: emu::MMAP_ADDR,
+                size: emu::MMAP_SIZE,
+                flags: PROT_READ | PROT_WRITE,
+                name: String::from("[mmap]"),
             })
-            .expect("Fail to hook interrupts"));
+            .expect("Failed to map mmap region");
+
+        let hook_kernel = kernel.clone();
+        let hook = match vmstate.arch {
+            // Syscalls are made with int 0x80 on x86.
+            Arch::X86 => {
+                vmstate.engine
+                    .borrow_mut()
+                    .add_intr_hook(move |engine, intno| if intno == 0x80 {
+                        hook_kernel.borrow_mut().on_syscall(engine);
+                    })
+            }
+            // And with svc on ARM.
+            Arch::Arm | Arch::Arm64 => {
+                vmstate.engine
+                    .borrow_mut()
+                    .add_intr_hook(move |engine, intno| if intno == EXCP_SWI {
+                        hook_kernel.borrow_mut().on_syscall(engine);
+                    })
+            }
+            Arch::Mips | Arch::Mipsel => {
+                vmstate.engine
+                    .borrow_mut()
+                    .add_intr_hook(move |engine, intno| {
+                        if intno == EXCP_SYSCALL {
+                            hook_kernel.borrow_mut().on_syscall(engine);
+                        }
+                    })
+            }
+            Arch::X86_64 => {
+                vmstate.engine
+                    .borrow_mut()
+                    .add_insn_sys_hook(InsnSysX86::SYSCALL,
+                                       1,
+                                       0,
+                                       move |engine| {
+                                           hook_kernel.borrow_mut()
+                                               .on_syscall(engine);
+                                       })
+            }
+        };
+        kernel.borrow_mut().intr_hook =
+            Some(hook.expect("Fail to hook interrupts"));
         return kernel;
     }
 }
@@ -342,6 +982,8 @@ impl Env for LinuxEnv {
 impl Kernel for LinuxKernel {
     fn reset(&mut self) -> Result<(), Error> {
         self.brk_ptr = emu::BRK_ADDR;
+        self.mmap_ptr = emu::MMAP_ADDR;
+        self.syscalls.clear();
         Ok(())
     }
 
@@ -354,4 +996,13 @@ impl Kernel for LinuxKernel {
             self.intr_hook = None;
         }
     }
+
+    fn syscalls(&self) -> Vec<SyscallRecord> {
+        return self.syscalls.clone();
+    }
+
+    fn heap_growth(&self) -> u64 {
+        return (self.brk_ptr - emu::BRK_ADDR) +
+               (self.mmap_ptr - emu::MMAP_ADDR);
+    }
 }
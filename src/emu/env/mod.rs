@@ -2,13 +2,72 @@ use emu::Error;
 use emu::vmstate::VmState;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::str::FromStr;
 
 pub mod linux;
 
+/// InitStrategy is how the program is initialized before its functions are
+/// called.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InitStrategy {
+    /// Run from the entry point up to `main`, or without its symbol up to
+    /// the main function given to the entry point's first call, the call to
+    /// the libc start function.
+    Main,
+    /// Run from the entry point up to an address.
+    Until(u64),
+    /// Run from the entry point up to a symbol.
+    UntilSymbol(String),
+    /// Run nothing, only set up the stack and the thread-local storage.
+    None,
+}
+
+impl Default for InitStrategy {
+    fn default() -> InitStrategy {
+        return InitStrategy::Main;
+    }
+}
+
+impl FromStr for InitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InitStrategy, String> {
+        return match s {
+            "" => Err(String::from("Empty init strategy")),
+            "main" => Ok(InitStrategy::Main),
+            "none" => Ok(InitStrategy::None),
+            _ if s.starts_with("0x") => {
+                u64::from_str_radix(&s[2..], 16)
+                    .map(InitStrategy::Until)
+                    .map_err(|_| format!("Invalid init address: {}", s))
+            }
+            _ => Ok(InitStrategy::UntilSymbol(String::from(s))),
+        };
+    }
+}
+
 pub trait Env {
     fn attach(&self, vmstate: &mut VmState) -> Rc<RefCell<Kernel>>;
 }
+/// SyscallRecord is a system call made by the emulated code.
+#[derive(Clone, Debug)]
+pub struct SyscallRecord {
+    pub number: u64,
+    pub args: Vec<u64>,
+    /// Guest memory read by the syscall, such as the written data or the
+    /// opened path.
+    pub data: Vec<u8>,
+    /// File descriptor |data| is written to, for syscalls writing to a file.
+    pub output_fd: Option<u64>,
+    pub result: u64,
+}
+
 pub trait Kernel {
     fn reset(&mut self) -> Result<(), Error>;
     fn detach(&mut self, vmstate: &mut VmState);
+    /// Syscalls made since the last reset, in order.
+    fn syscalls(&self) -> Vec<SyscallRecord>;
+    /// Bytes of memory given to the program since the last reset, such as
+    /// the heap growth.
+    fn heap_growth(&self) -> u64;
 }
@@ -0,0 +1,126 @@
+use emu::object_info::ObjectInfo;
+use std::fmt;
+use unicorn;
+
+/// Fault is the reason an emulated call stopped before returning.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Read of unmapped or protected memory at |addr|.
+    InvalidRead { addr: u64, map: Option<String> },
+    /// Write to unmapped or protected memory at |addr|.
+    InvalidWrite { addr: u64, map: Option<String> },
+    /// Execution of an invalid instruction, or of unmapped or non-executable
+    /// memory, at |addr|.
+    InvalidInstruction { addr: u64, map: Option<String> },
+    /// The call exceeded its maximum number of instructions.
+    MaxCountReached,
+    /// The call exceeded its timeout.
+    Timeout,
+    /// Any other engine error, such as an unhandled interrupt.
+    Other(String),
+}
+
+impl Fault {
+    /// Fault of an emulation stopped by |err|. |ip| is the address of the
+    /// faulting instruction and |access| the address of the last invalid
+    /// memory access, if any.
+    pub fn from_error(err: unicorn::Error,
+                      ip: u64,
+                      access: Option<u64>,
+                      object_info: &ObjectInfo)
+                      -> Fault {
+        let map_at = |addr: u64| {
+            object_info.mem_map_at(addr).map(|m| m.name.clone())
+        };
+        let addr = access.unwrap_or(ip);
+        return match err {
+            unicorn::Error::READ_UNMAPPED |
+            unicorn::Error::READ_PROT |
+            unicorn::Error::READ_UNALIGNED => {
+                Fault::InvalidRead {
+                    addr: addr,
+                    map: map_at(addr),
+                }
+            }
+            unicorn::Error::WRITE_UNMAPPED |
+            unicorn::Error::WRITE_PROT |
+            unicorn::Error::WRITE_UNALIGNED => {
+                Fault::InvalidWrite {
+                    addr: addr,
+                    map: map_at(addr),
+                }
+            }
+            unicorn::Error::FETCH_UNMAPPED |
+            unicorn::Error::FETCH_PROT |
+            unicorn::Error::FETCH_UNALIGNED => {
+                Fault::InvalidInstruction {
+                    addr: addr,
+                    map: map_at(addr),
+                }
+            }
+            unicorn::Error::INSN_INVALID => {
+                Fault::InvalidInstruction {
+                    addr: ip,
+                    map: map_at(ip),
+                }
+            }
+            err => Fault::Other(format!("{:?}", err)),
+        };
+    }
+
+    /// Short name of the fault, as seen by the rules.
+    pub fn kind(&self) -> &'static str {
+        return match *self {
+            Fault::InvalidRead { .. } => "read",
+            Fault::InvalidWrite { .. } => "write",
+            Fault::InvalidInstruction { .. } => "instruction",
+            Fault::MaxCountReached => "max_count",
+            Fault::Timeout => "timeout",
+            Fault::Other(_) => "other",
+        };
+    }
+
+    /// Address of the faulting access, if any.
+    pub fn addr(&self) -> Option<u64> {
+        return match *self {
+            Fault::InvalidRead { addr, .. } |
+            Fault::InvalidWrite { addr, .. } |
+            Fault::InvalidInstruction { addr, .. } => Some(addr),
+            _ => None,
+        };
+    }
+
+    /// Name of the mapping holding the faulting address, if it is mapped.
+    pub fn map(&self) -> Option<&str> {
+        return match *self {
+            Fault::InvalidRead { ref map, .. } |
+            Fault::InvalidWrite { ref map, .. } |
+            Fault::InvalidInstruction { ref map, .. } => {
+                map.as_ref().map(|m| m.as_str())
+            }
+            _ => None,
+        };
+    }
+
+    /// Whether the call was stopped by its emulation limits rather than by
+    /// its own doing.
+    pub fn is_limit(&self) -> bool {
+        return *self == Fault::MaxCountReached || *self == Fault::Timeout;
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Fault::Other(ref err) = *self {
+            return write!(f, "engine error {}", err);
+        }
+        try!(write!(f, "{}", self.kind()));
+        if let Some(addr) = self.addr() {
+            try!(write!(f, " fault at 0x{:x}", addr));
+        }
+        if let Some(map) = self.map() {
+            try!(write!(f, " in {}", map));
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,143 @@
+use emu::Error;
+use emu::args::GuardedArg;
+use emu::dirty_pages::PAGE_SIZE;
+use std::cell::RefCell;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::{MemHookType, MemType, PROT_NONE, PROT_READ,
+                             PROT_WRITE};
+
+/// OutOfBounds is an access of a call outside of a guarded argument's data.
+#[derive(Clone, Debug)]
+pub struct OutOfBounds {
+    /// Index of the argument.
+    pub arg: usize,
+    /// Offset of the access from the start of the argument's data, negative
+    /// for underflows.
+    pub offset: i64,
+    pub size: usize,
+    pub write: bool,
+}
+
+#[derive(Default)]
+struct GuardState {
+    args: Vec<GuardedArg>,
+    pages: Vec<u64>,
+    accesses: Vec<OutOfBounds>,
+}
+
+/// GuardPages records the accesses to the guard pages around the arguments.
+///
+/// The guard pages lose every permission during the call. Their accesses are
+/// reported to the memory hook, recorded, then let through so the call goes
+/// on.
+pub struct GuardPages {
+    engine: Rc<RefCell<unicorn::Unicorn>>,
+    state: Rc<RefCell<GuardState>>,
+    hook: Option<unicorn::uc_hook>,
+}
+
+impl GuardPages {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>) -> GuardPages {
+        return GuardPages {
+            engine: engine,
+            state: Default::default(),
+            hook: None,
+        };
+    }
+
+    pub fn attach(&mut self) -> Result<(), Error> {
+        if let Some(_) = self.hook {
+            return Ok(());
+        }
+
+        let state = self.state.clone();
+        self.hook = Some(try!(self.engine
+            .borrow_mut()
+            .add_mem_hook(MemHookType::MEM_INVALID,
+                          1,
+                          0,
+                          move |_, mem_type, address, size, _| {
+                let write = match mem_type {
+                    MemType::WRITE_PROT => true,
+                    MemType::READ_PROT => false,
+                    _ => return false,
+                };
+                let mut state = state.borrow_mut();
+                let end = address + size as u64;
+                if !state.pages
+                    .iter()
+                    .any(|&page| address < page + PAGE_SIZE && end > page) {
+                    return false;
+                }
+
+                let arg = nearest_arg(&state.args, address)
+                    .map(|a| (a.index, address as i64 - a.addr as i64));
+                if let Some((index, offset)) = arg {
+                    state.accesses.push(OutOfBounds {
+                        arg: index,
+                        offset: offset,
+                        size: size,
+                        write: write,
+                    });
+                }
+                return true;
+            })));
+        return Ok(());
+    }
+
+    pub fn detach(&mut self) -> Result<(), Error> {
+        if let Some(hook) = self.hook {
+            try!(self.engine.borrow_mut().remove_hook(hook));
+            self.hook = None;
+        }
+        return Ok(());
+    }
+
+    /// Take every permission from |pages| until the next reset, and report
+    /// their accesses as out of the bounds of |args|.
+    pub fn guard(&self,
+                 args: &[GuardedArg],
+                 pages: &[u64])
+                 -> Result<(), Error> {
+        let engine = self.engine.borrow();
+        for &page in pages {
+            try!(engine.mem_protect(page, PAGE_SIZE as usize, PROT_NONE));
+        }
+
+        let mut state = self.state.borrow_mut();
+        state.args = args.to_vec();
+        state.pages = pages.to_vec();
+        state.accesses.clear();
+        return Ok(());
+    }
+
+    /// Give the guard pages their permissions back and forget the recorded
+    /// accesses.
+    pub fn reset(&self) -> Result<(), Error> {
+        let engine = self.engine.borrow();
+        let mut state = self.state.borrow_mut();
+        for page in state.pages.drain(..) {
+            try!(engine.mem_protect(page,
+                                    PAGE_SIZE as usize,
+                                    PROT_READ | PROT_WRITE));
+        }
+        state.args.clear();
+        state.accesses.clear();
+        return Ok(());
+    }
+
+    /// Accesses out of the guarded arguments since the last reset, in order.
+    pub fn accesses(&self) -> Vec<OutOfBounds> {
+        return self.state.borrow().accesses.clone();
+    }
+}
+
+/// Guarded argument closest to |addr|, an address out of all of them.
+fn nearest_arg(args: &[GuardedArg], addr: u64) -> Option<&GuardedArg> {
+    return args.iter().min_by_key(|a| if addr < a.addr {
+        a.addr - addr
+    } else {
+        addr.saturating_sub(a.addr + a.size)
+    });
+}
@@ -0,0 +1,345 @@
+use emu;
+use emu::Error;
+use emu::arch::Arch;
+use emu::dirty_pages::DirtyPages;
+use emu::object_info::ObjectInfo;
+use std::cell::RefCell;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::CodeHookType;
+use unicorn::arm_const::RegisterARM;
+use unicorn::arm64_const::RegisterARM64;
+use unicorn::mips_const::RegisterMIPS;
+use unicorn::x86_const::RegisterX86 as RegEnum;
+
+/// Allocator is the kind of a known allocator entry point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Allocator {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+    /// `operator new` and `operator new[]`.
+    New,
+    /// `operator delete` and `operator delete[]`.
+    Delete,
+}
+
+impl Allocator {
+    /// Returns the allocator implemented by the symbol |name|, if any.
+    pub fn from_symbol(name: &str) -> Option<Allocator> {
+        return match name {
+            "malloc" => Some(Allocator::Malloc),
+            "calloc" => Some(Allocator::Calloc),
+            "realloc" => Some(Allocator::Realloc),
+            "free" => Some(Allocator::Free),
+            "_Znwm" | "_Znam" | "_Znwj" | "_Znaj" => Some(Allocator::New),
+            "_ZdlPv" | "_ZdaPv" | "_ZdlPvm" | "_ZdaPvm" | "_ZdlPvj" |
+            "_ZdaPvj" => Some(Allocator::Delete),
+            _ => None,
+        };
+    }
+
+    fn frees(&self) -> bool {
+        return *self == Allocator::Free || *self == Allocator::Delete;
+    }
+}
+
+/// Allocation is a block returned by an allocator during a call.
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    pub addr: u64,
+    pub size: u64,
+    /// Whether the block was freed before the call returned.
+    pub freed: bool,
+}
+
+/// HeapEffects describes how a call used the heap.
+#[derive(Clone, Debug, Default)]
+pub struct HeapEffects {
+    /// Blocks allocated by the call, in order.
+    pub allocs: Vec<Allocation>,
+    /// Blocks freed by the call, including blocks allocated before it.
+    pub frees: Vec<u64>,
+    /// Bytes of memory obtained from the kernel with brk and mmap.
+    pub growth: u64,
+}
+
+impl HeapEffects {
+    /// Allocations not freed by the call.
+    pub fn live(&self) -> Vec<&Allocation> {
+        return self.allocs.iter().filter(|a| !a.freed).collect();
+    }
+}
+
+/// Allocator call waiting for its return value.
+struct PendingCall {
+    allocator: Allocator,
+    args: [u64; 2],
+    return_addr: u64,
+}
+
+#[derive(Default)]
+struct HeapState {
+    pending: Option<PendingCall>,
+    allocs: Vec<Allocation>,
+    frees: Vec<u64>,
+    /// Error of a hook, which stopped the emulation.
+    error: Option<Error>,
+}
+
+/// HeapTracker records the allocations and frees done through the known
+/// allocator entry points.
+///
+/// The entry points are hooked, and the return address of the allocator
+/// calls is replaced by `ALLOC_TRAMPOLINE` to catch their return value before
+/// resuming at the real return address. Only the outermost allocator calls
+/// are recorded, so `operator new` calling `malloc` is a single allocation
+/// and `operator delete` calling `free` a single free.
+pub struct HeapTracker {
+    engine: Rc<RefCell<unicorn::Unicorn>>,
+    arch: Arch,
+    dirty_pages: DirtyPages,
+    state: Rc<RefCell<HeapState>>,
+    entry_hooks: Vec<(u64, unicorn::uc_hook)>,
+    trampoline_hook: Option<unicorn::uc_hook>,
+}
+
+impl HeapTracker {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>,
+               arch: Arch,
+               dirty_pages: DirtyPages)
+               -> HeapTracker {
+        return HeapTracker {
+            engine: engine,
+            arch: arch,
+            dirty_pages: dirty_pages,
+            state: Default::default(),
+            entry_hooks: Vec::new(),
+            trampoline_hook: None,
+        };
+    }
+
+    /// Hook the allocators found in the binary's symbols.
+    pub fn attach(&mut self, object_info: &ObjectInfo) -> Result<(), Error> {
+        let state = self.state.clone();
+        let arch = self.arch;
+        self.trampoline_hook = Some(try!(self.engine
+            .borrow_mut()
+            .add_code_hook(CodeHookType::CODE,
+                           emu::ALLOC_TRAMPOLINE,
+                           emu::ALLOC_TRAMPOLINE,
+                           move |engine, _, _| {
+                let r = HeapTracker::on_return(engine, arch, &state);
+                HeapTracker::check_hook(engine, &state, r);
+            })));
+
+        let mut allocators: Vec<(u64, Allocator)> = object_info.symbols
+            .values()
+            .filter(|s| s.value != 0)
+            .filter_map(|s| {
+                Allocator::from_symbol(&s.name).map(|a| (s.value, a))
+            })
+            .collect();
+        allocators.sort_by_key(|&(addr, _)| addr);
+        for (addr, allocator) in allocators {
+            try!(self.set_allocator(addr, allocator));
+        }
+        return Ok(());
+    }
+
+    /// Hook |addr| as an |allocator| entry point. Used to track the
+    /// allocators identified in a stripped binary.
+    pub fn set_allocator(&mut self,
+                         addr: u64,
+                         allocator: Allocator)
+                         -> Result<(), Error> {
+        let addr = self.arch.code_addr(addr);
+        if self.entry_hooks.iter().any(|&(a, _)| a == addr) {
+            return Ok(());
+        }
+        let state = self.state.clone();
+        let dirty_pages = self.dirty_pages.clone();
+        let arch = self.arch;
+        let hook = try!(self.engine
+            .borrow_mut()
+            .add_code_hook(CodeHookType::CODE,
+                           addr,
+                           addr,
+                           move |engine, _, _| {
+                let r = HeapTracker::on_entry(engine,
+                                              arch,
+                                              &state,
+                                              &dirty_pages,
+                                              allocator);
+                HeapTracker::check_hook(engine, &state, r);
+            }));
+        self.entry_hooks.push((addr, hook));
+        return Ok(());
+    }
+
+    pub fn detach(&mut self) -> Result<(), Error> {
+        let mut engine = self.engine.borrow_mut();
+        for (_, hook) in self.entry_hooks.drain(..) {
+            try!(engine.remove_hook(hook));
+        }
+        if let Some(hook) = self.trampoline_hook {
+            try!(engine.remove_hook(hook));
+            self.trampoline_hook = None;
+        }
+        return Ok(());
+    }
+
+    /// Forget the recorded allocations.
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.pending = None;
+        state.allocs.clear();
+        state.frees.clear();
+        state.error = None;
+    }
+
+    /// Error of a hook since the last reset. The emulation was stopped on
+    /// it, so the call's outcome is meaningless.
+    pub fn take_error(&self) -> Result<(), Error> {
+        return match self.state.borrow_mut().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    /// Returns the heap usage since the last reset. |growth| is the memory
+    /// obtained from the kernel.
+    pub fn effects(&self, growth: u64) -> HeapEffects {
+        let state = self.state.borrow();
+        return HeapEffects {
+            allocs: state.allocs.clone(),
+            frees: state.frees.clone(),
+            growth: growth,
+        };
+    }
+
+    /// Record the error |r| of a hook and stop the emulation on it, instead
+    /// of panicking through the engine.
+    fn check_hook(engine: &unicorn::Unicorn,
+                  state: &RefCell<HeapState>,
+                  r: Result<(), Error>) {
+        if let Err(e) = r {
+            state.borrow_mut().error = Some(e);
+            let _ = engine.emu_stop();
+        }
+    }
+
+    fn on_entry(engine: &unicorn::Unicorn,
+                arch: Arch,
+                state: &RefCell<HeapState>,
+                dirty_pages: &DirtyPages,
+                allocator: Allocator)
+                -> Result<(), Error> {
+        let mut state = state.borrow_mut();
+        // Nested allocator calls are part of the outermost one.
+        if state.pending.is_some() {
+            return Ok(());
+        }
+
+        let sp = try!(engine.reg_read(arch.sp_reg()));
+        let args = match arch {
+            // cdecl, the arguments follow the return address.
+            Arch::X86 => {
+                let args = try!(engine.mem_read(sp + 4, 8));
+                [arch.unpack(&args[..4]), arch.unpack(&args[4..])]
+            }
+            Arch::X86_64 => {
+                [try!(engine.reg_read(RegEnum::RDI as i32)),
+                 try!(engine.reg_read(RegEnum::RSI as i32))]
+            }
+            Arch::Arm => {
+                [try!(engine.reg_read(RegisterARM::R0 as i32)),
+                 try!(engine.reg_read(RegisterARM::R1 as i32))]
+            }
+            Arch::Arm64 => {
+                [try!(engine.reg_read(RegisterARM64::X0 as i32)),
+                 try!(engine.reg_read(RegisterARM64::X1 as i32))]
+            }
+            Arch::Mips | Arch::Mipsel => {
+                [try!(engine.reg_read(RegisterMIPS::A0 as i32)),
+                 try!(engine.reg_read(RegisterMIPS::A1 as i32))]
+            }
+        };
+        if allocator.frees() && args[0] != 0 {
+            state.free(args[0]);
+        }
+
+        // Catch the return even for frees, to ignore their nested calls.
+        let return_addr = match arch.link_reg() {
+            Some(reg) => {
+                let return_addr = try!(engine.reg_read(reg));
+                try!(engine.reg_write(reg, emu::ALLOC_TRAMPOLINE));
+                return_addr
+            }
+            None => {
+                let return_addr = try!(engine.mem_read(sp,
+                                                       arch.pointer_size()));
+                let trampoline = arch.pack(emu::ALLOC_TRAMPOLINE);
+                try!(engine.mem_write(sp, &trampoline));
+                dirty_pages.mark(sp, trampoline.len());
+                arch.unpack(&return_addr)
+            }
+        };
+
+        state.pending = Some(PendingCall {
+            allocator: allocator,
+            args: args,
+            return_addr: return_addr,
+        });
+        return Ok(());
+    }
+
+    fn on_return(engine: &unicorn::Unicorn,
+                 arch: Arch,
+                 state: &RefCell<HeapState>)
+                 -> Result<(), Error> {
+        let mut state = state.borrow_mut();
+        let call = match state.pending.take() {
+            Some(call) => call,
+            None => return Ok(()),
+        };
+
+        if call.allocator.frees() {
+            try!(engine.reg_write(arch.ip_reg(), call.return_addr));
+            return Ok(());
+        }
+
+        let addr = try!(engine.reg_read(arch.return_reg()));
+        let size = match call.allocator {
+            Allocator::Calloc => call.args[0].wrapping_mul(call.args[1]),
+            Allocator::Realloc => call.args[1],
+            _ => call.args[0],
+        };
+        if addr != 0 {
+            if call.allocator == Allocator::Realloc && call.args[0] != 0 {
+                state.free(call.args[0]);
+            }
+            state.allocs.push(Allocation {
+                addr: addr,
+                size: size,
+                freed: false,
+            });
+        }
+
+        try!(engine.reg_write(arch.ip_reg(), call.return_addr));
+        return Ok(());
+    }
+}
+
+impl HeapState {
+    fn free(&mut self, addr: u64) {
+        self.frees.push(addr);
+        if let Some(alloc) = self.allocs
+            .iter_mut()
+            .rev()
+            .find(|a| a.addr == addr && !a.freed) {
+            alloc.freed = true;
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use utils::LogError;
+use emu;
+use emu::arch::Arch;
 use emu::loader::Error;
-use emu::object_info::MemMap;
+use emu::object_info::{MemMap, TlsTemplate};
 use emu::vmstate::VmState;
 use elf;
 use std::io;
@@ -10,6 +12,26 @@ use std::path::Path;
 use std::rc::Rc;
 use unicorn;
 
+/// Offset of e_flags in the header of 32 bits ELF files.
+const ELF32_FLAGS_OFFSET: u64 = 0x24;
+/// ARM e_flags bit of the binaries using the hard-float ABI.
+const EF_ARM_ABI_FLOAT_HARD: u32 = 0x400;
+
+/// Dynamic section tag of the MIPS GOT address.
+const DT_PLTGOT: u64 = 3;
+/// Dynamic section tags of the MIPS GOT layout.
+const DT_MIPS_LOCAL_GOTNO: u64 = 0x7000000a;
+const DT_MIPS_SYMTABNO: u64 = 0x70000011;
+const DT_MIPS_GOTSYM: u64 = 0x70000013;
+
+/// Address |elf_file| is loaded at, to add to its link addresses.
+pub fn load_base(elf_file: &elf::File) -> u64 {
+    if elf_file.ehdr.elftype == elf::types::ET_DYN {
+        return emu::DYN_BASE;
+    }
+    return 0;
+}
+
 /// Align a memory size.
 fn aligned_size(size: usize, page_size: usize) -> usize {
     return (size / page_size + 1) * page_size;
@@ -37,41 +59,33 @@ fn prot_from_elf_flags(flag: elf::types::ProgFlag)
     return prot;
 }
 
-struct Arch(unicorn::unicorn_const::Arch, unicorn::unicorn_const::Mode);
-
-impl Arch {
-    /// Create a unicorn::Unicorn instance given a Machine's arch type.
-    pub fn new(arch: elf::types::Machine) -> Result<Arch, Error> {
-        use unicorn::unicorn_const::{Arch, Mode};
-        match arch {
-            elf::types::EM_386 => Ok(Arch(Arch::X86, Mode::MODE_32)),
-            elf::types::EM_X86_64 => Ok(Arch(Arch::X86, Mode::MODE_64)),
-            _ => Err(Error::UnsupportedArch(arch)),
-        }
-    }
-}
-
 pub fn load(path: &Path) -> Result<VmState, Error> {
     use std::io::{Read, Seek};
 
     let elf_file = try!(elf::File::open_path(path));
 
-    let emu = Rc::new(RefCell::new(match try!(Arch::new(elf_file.ehdr
-        .machine)) {
-        Arch(arch, mode) => try!(unicorn::Unicorn::new(arch, mode)),
-    }));
+    let arch = match Arch::from_elf(elf_file.ehdr.machine,
+                                    elf_file.ehdr.data) {
+        Some(arch) => arch,
+        None => return Err(Error::UnsupportedArch(elf_file.ehdr.machine)),
+    };
+    let engine = try!(unicorn::Unicorn::new(arch.unicorn_arch(),
+                                            arch.unicorn_mode()));
+    let emu = Rc::new(RefCell::new(engine));
 
-    let mut vmstate = VmState::new(emu.clone());
+    let mut vmstate = VmState::new(emu.clone(), arch);
 
     // unwrap, we open it once, should open again...
     let mut file_stream = File::open(path).unwrap();
+    let base = load_base(&elf_file);
 
     // Load segment in emulator.
     let loadable_segments =
         elf_file.phdrs.iter().filter(|s| s.progtype == elf::types::PT_LOAD);
     for phdr in loadable_segments {
-        let page_addr = aligned_addr(phdr.vaddr, 0x1000);
-        let offset = (phdr.vaddr - page_addr) as usize;
+        let vaddr = phdr.vaddr + base;
+        let page_addr = aligned_addr(vaddr, 0x1000);
+        let offset = (vaddr - page_addr) as usize;
         let page_size = aligned_size(phdr.memsz as usize + offset, 0x1000);
         let flags = prot_from_elf_flags(phdr.flags);
         try!(vmstate.mem_map(MemMap {
@@ -95,22 +109,158 @@ pub fn load(path: &Path) -> Result<VmState, Error> {
             }));
 
         try!(emu.borrow()
-            .mem_write(phdr.vaddr, data_buf.as_slice())
+            .mem_write(vaddr, data_buf.as_slice())
             .log_err(|_| {
                 format!("Failed to write segment to emulator: {:?}", phdr)
             }));
     }
 
+    vmstate.object_info.entry = elf_file.ehdr.entry + base;
+    if arch == Arch::Arm {
+        // The ELF header flags are not parsed, read them ourselves.
+        let mut flags = [0; 4];
+        try!(file_stream.seek(io::SeekFrom::Start(ELF32_FLAGS_OFFSET))
+            .and_then(|_| file_stream.read_exact(&mut flags))
+            .log_err(|_| String::from("Failed to read the ELF flags")));
+        let flags = arch.endian().read_u32(&flags);
+        vmstate.object_info.hard_float = flags & EF_ARM_ABI_FLOAT_HARD != 0;
+    }
+    vmstate.object_info.tls = elf_file.phdrs
+        .iter()
+        .find(|s| s.progtype == elf::types::PT_TLS)
+        .map(|phdr| {
+            TlsTemplate {
+                addr: phdr.vaddr + base,
+                file_size: phdr.filesz as usize,
+                mem_size: phdr.memsz as usize,
+                align: ::std::cmp::max(phdr.align, 1),
+            }
+        });
+    if elf_file.ehdr.elftype == elf::types::ET_DYN {
+        try!(apply_relocations(&vmstate, &elf_file, base));
+    }
+
     // Create symbols hashmap.
     if let Some(symtab) = elf_file.get_section(".symtab") {
         for symbol in elf_file.get_symbols(symtab)
             .expect("Failed to parse .symtab")
             .iter() {
-            vmstate.object_info
-                .symbols
-                .insert(symbol.name.clone(), symbol.clone());
+            let mut symbol = symbol.clone();
+            // TLS symbols are offsets in the TLS block, not addresses.
+            if symbol.value != 0 && symbol.symtype != elf::types::STT_TLS {
+                symbol.value += base;
+            }
+            vmstate.object_info.add_symbol(symbol);
         }
     }
 
     return Ok(vmstate);
 }
+
+/// Apply the relative relocations of a position independent binary loaded at
+/// |base|: each relocated pointer is set to its addend, or to the value in
+/// place for REL relocations, plus |base|.
+fn apply_relocations(vmstate: &VmState,
+                     elf_file: &elf::File,
+                     base: u64)
+                     -> Result<(), Error> {
+    let arch = vmstate.arch;
+    let relative = match arch {
+        Arch::X86 | Arch::X86_64 => 8,
+        Arch::Arm => 23,
+        Arch::Arm64 => 1027,
+        Arch::Mips | Arch::Mipsel => 3,
+    };
+    let endian = arch.endian();
+    let is_64 = arch.pointer_size() == 8;
+    let sections = elf_file.sections
+        .iter()
+        .filter(|s| {
+            s.shdr.shtype == elf::types::SHT_RELA ||
+            s.shdr.shtype == elf::types::SHT_REL
+        });
+    for section in sections {
+        let rela = section.shdr.shtype == elf::types::SHT_RELA;
+        let entry_size = match (is_64, rela) {
+            (true, true) => 24,
+            (true, false) => 16,
+            (false, true) => 12,
+            (false, false) => 8,
+        };
+        for entry in section.data.chunks(entry_size) {
+            if entry.len() < entry_size {
+                break;
+            }
+            let (offset, kind) = if is_64 {
+                (endian.read_u64(&entry[0..8]),
+                 endian.read_u64(&entry[8..16]) & 0xffffffff)
+            } else {
+                (endian.read_u32(&entry[0..4]) as u64,
+                 (endian.read_u32(&entry[4..8]) & 0xff) as u64)
+            };
+            if kind != relative {
+                continue;
+            }
+            let addr = offset + base;
+            let value = if !rela {
+                try!(vmstate.read_usize(addr))
+            } else if is_64 {
+                endian.read_u64(&entry[16..24])
+            } else {
+                endian.read_u32(&entry[8..12]) as u64
+            };
+            try!(vmstate.mem_write(addr, &arch.pack(value + base)));
+        }
+    }
+    if arch == Arch::Mips || arch == Arch::Mipsel {
+        try!(relocate_mips_got(vmstate, elf_file, base));
+    }
+    return Ok(());
+}
+
+/// Add |base| to the MIPS GOT entries, which have no relocations: the local
+/// entries and the ones of the global symbols, skipping the reserved ones and
+/// the undefined symbols left to 0.
+fn relocate_mips_got(vmstate: &VmState,
+                     elf_file: &elf::File,
+                     base: u64)
+                     -> Result<(), Error> {
+    let dynamic = match elf_file.get_section(".dynamic") {
+        Some(dynamic) => dynamic,
+        None => return Ok(()),
+    };
+    let endian = vmstate.arch.endian();
+    let (mut got, mut local_gotno, mut symtabno, mut gotsym) = (0, 0, 0, 0);
+    for entry in dynamic.data.chunks(8) {
+        if entry.len() < 8 {
+            break;
+        }
+        let value = endian.read_u32(&entry[4..8]) as u64;
+        match endian.read_u32(&entry[0..4]) as u64 {
+            DT_PLTGOT => got = value,
+            DT_MIPS_LOCAL_GOTNO => local_gotno = value,
+            DT_MIPS_SYMTABNO => symtabno = value,
+            DT_MIPS_GOTSYM => gotsym = value,
+            _ => (),
+        }
+    }
+    if got == 0 {
+        return Ok(());
+    }
+    let got = got + base;
+    // The second entry is reserved to GNU's loader when its top bit is set.
+    let first = if try!(vmstate.read_usize(got + 4)) & 0x80000000 != 0 {
+        2
+    } else {
+        1
+    };
+    let count = local_gotno + symtabno.saturating_sub(gotsym);
+    for index in first..count {
+        let addr = got + index * 4;
+        let value = try!(vmstate.read_usize(addr));
+        if value != 0 {
+            try!(vmstate.mem_write(addr, &vmstate.arch.pack(value + base)));
+        }
+    }
+    return Ok(());
+}
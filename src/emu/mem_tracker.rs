@@ -0,0 +1,137 @@
+use emu::Error;
+use emu::object_info::ObjectInfo;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::MemHookType;
+
+/// MemWrite is a contiguous memory region written by an emulated call.
+#[derive(Clone, Debug)]
+pub struct MemWrite {
+    pub addr: u64,
+    /// Content of the region before the call.
+    pub old: Vec<u8>,
+    /// Content of the region after the call.
+    pub new: Vec<u8>,
+    /// Name of the mapping containing the region.
+    pub map: String,
+}
+
+impl MemWrite {
+    pub fn size(&self) -> usize {
+        return self.old.len();
+    }
+
+    /// Whether the region content changed in [addr, addr + size).
+    pub fn changed(&self, addr: u64, size: u64) -> bool {
+        let start = ::std::cmp::max(addr, self.addr);
+        let end = ::std::cmp::min(addr.saturating_add(size),
+                                  self.addr + self.size() as u64);
+        return (start..end).any(|a| {
+            let i = (a - self.addr) as usize;
+            self.old[i] != self.new[i]
+        });
+    }
+}
+
+/// MemTracker records the memory written by the emulated code.
+///
+/// Only the writes done by emulated instructions are seen, not the ones done
+/// through the engine, such as the arguments setup or the kernel's syscalls.
+pub struct MemTracker {
+    hook: Option<unicorn::uc_hook>,
+    /// Value of each written byte before its first write.
+    old_bytes: Rc<RefCell<BTreeMap<u64, u8>>>,
+    /// Range of addresses whose writes are ignored.
+    ignored: Rc<Cell<(u64, u64)>>,
+    engine: Rc<RefCell<unicorn::Unicorn>>,
+}
+
+impl MemTracker {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>) -> MemTracker {
+        return MemTracker {
+            hook: None,
+            old_bytes: Default::default(),
+            ignored: Rc::new(Cell::new((0, 0))),
+            engine: engine,
+        };
+    }
+
+    pub fn attach(&mut self) -> Result<(), Error> {
+        if let Some(_) = self.hook {
+            return Ok(());
+        }
+
+        let old_bytes = self.old_bytes.clone();
+        let ignored = self.ignored.clone();
+        self.hook = Some(try!(self.engine
+            .borrow_mut()
+            .add_mem_hook(MemHookType::MEM_WRITE,
+                          1,
+                          0,
+                          move |engine, _, address, size, _| {
+                let (ignored_start, ignored_end) = ignored.get();
+                let mut old_bytes = old_bytes.borrow_mut();
+                for addr in address..address + size as u64 {
+                    if (addr >= ignored_start && addr < ignored_end) ||
+                       old_bytes.contains_key(&addr) {
+                        continue;
+                    }
+                    // The hook runs before the write, the memory still holds
+                    // the old value.
+                    if let Ok(old) = engine.mem_read(addr, 1) {
+                        old_bytes.insert(addr, old[0]);
+                    }
+                }
+                return true;
+            })));
+        return Ok(());
+    }
+
+    pub fn detach(&mut self) -> Result<(), Error> {
+        if let Some(hook) = self.hook {
+            try!(self.engine.borrow_mut().remove_hook(hook));
+            self.hook = None;
+        }
+        return Ok(());
+    }
+
+    /// Forget the recorded writes and ignore the subsequent ones in
+    /// [ignored_start, ignored_end).
+    pub fn reset(&self, ignored_start: u64, ignored_end: u64) {
+        self.old_bytes.borrow_mut().clear();
+        self.ignored.set((ignored_start, ignored_end));
+    }
+
+    /// Returns the regions written since the last reset, in address order.
+    pub fn writes(&self,
+                  object_info: &ObjectInfo)
+                  -> Result<Vec<MemWrite>, Error> {
+        let mut writes: Vec<MemWrite> = Vec::new();
+        for (&addr, &old) in self.old_bytes.borrow().iter() {
+            let map = match object_info.mem_map_at(addr) {
+                Some(map) => map.name.clone(),
+                None => String::new(),
+            };
+            if let Some(last) = writes.last_mut() {
+                if last.addr + last.size() as u64 == addr && last.map == map {
+                    last.old.push(old);
+                    continue;
+                }
+            }
+            writes.push(MemWrite {
+                addr: addr,
+                old: vec![old],
+                new: Vec::new(),
+                map: map,
+            });
+        }
+
+        let engine = self.engine.borrow();
+        for write in &mut writes {
+            write.new = try!(engine.mem_read(write.addr, write.size()));
+        }
+        return Ok(writes);
+    }
+}
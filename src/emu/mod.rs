@@ -1,11 +1,19 @@
+pub mod arch;
 pub mod args;
+pub mod call_tracer;
 pub mod calling_convention;
 pub mod datatypes;
 pub mod debugger;
+pub mod dirty_pages;
 pub mod emu_engine;
 pub mod env;
+pub mod fault;
+pub mod guard_pages;
+pub mod heap_tracker;
 pub mod loader;
+pub mod mem_tracker;
 pub mod object_info;
+pub mod shadow_memory;
 pub mod vmstate;
 
 use std::path::Path;
@@ -13,7 +21,12 @@ use std::path::Path;
 pub const STACK_ADDR: u64 = 0x10000000;
 pub const STACK_SIZE: usize = 0x10000;
 pub const EMUDATA_ADDR: u64 = 0x11000000;
+/// Initial size of the arguments data, grown as needed up to
+/// `EMUDATA_MAX_SIZE`.
 pub const EMUDATA_SIZE: usize = 0x10000;
+pub const EMUDATA_MAX_SIZE: usize = 0x1000000;
+/// Alignment of each argument written in the arguments data.
+pub const EMUDATA_ALIGN: u64 = 0x10;
 pub const KERNEL_ADDR: u64 = 0x12000000;
 pub const KERNEL_SIZE: usize = 0x10000;
 pub const TLS_ADDR: u64 = 0x13000000;
@@ -22,11 +35,41 @@ pub const SHELLCODE_ADDR: u64 = 0x14000000;
 pub const SHELLCODE_SIZE: usize = 0x10000;
 pub const BRK_ADDR: u64 = 0x20000000;
 pub const BRK_SIZE: usize = 0x100000;
+pub const MMAP_ADDR: u64 = 0x30000000;
+pub const MMAP_SIZE: usize = 0x400000;
+
+/// Address position independent binaries and shared objects are loaded at,
+/// rather than their link address 0, so that NULL pointers still fault.
+pub const DYN_BASE: u64 = 0x40000000;
 
 pub const CODE_SENTINEL: u64 = 0x80000000;
+/// Return address given to the hooked allocator calls, in the code sentinel
+/// page.
+pub const ALLOC_TRAMPOLINE: u64 = CODE_SENTINEL + 0x10;
 pub const EMU_TIMEOUT: u64 = 1 * 1000 * 1000; // 1 sec.
 pub const EMU_MAXCOUNT: usize = 0x10000;
 
+/// EmuLimits bounds the emulation of a single call. A limit of 0 disables it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmuLimits {
+    /// Timeout, in microseconds.
+    pub timeout: u64,
+    /// Maximum number of emulated instructions.
+    pub max_count: usize,
+    /// Maximum size of the arguments data, in bytes. It can't exceed
+    /// `EMUDATA_MAX_SIZE`.
+    pub max_data: usize,
+}
+
+impl Default for EmuLimits {
+    fn default() -> EmuLimits {
+        return EmuLimits {
+            timeout: EMU_TIMEOUT,
+            max_count: EMU_MAXCOUNT,
+            max_data: EMUDATA_MAX_SIZE,
+        };
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -34,7 +77,13 @@ pub enum Error {
     MapAlreadyExists,
     StackUninitialized,
     EmuDataUninitialized,
-    ExecError(::unicorn::unicorn_const::Error),
+    /// The arguments don't fit in the maximum size of the arguments data.
+    EmuDataOverflow,
+    /// The symbol the program initialization needs is missing.
+    SymbolNotFound(String),
+    /// The program initialization failed, with the engine's error, or
+    /// didn't reach where it should stop.
+    InitError(Option<::unicorn::unicorn_const::Error>),
     FromUtf8Error(::std::string::FromUtf8Error),
     NotImplemented,
 }
@@ -54,7 +103,15 @@ impl ::std::convert::From<::std::string::FromUtf8Error> for Error {
 /// Helper function to create and initialize an emulation context from an elf
 /// binary.
 pub fn from_elf(path: &Path) -> Result<emu_engine::EmuEngine, loader::Error> {
-    let vmstate = try!(self::loader::elf::load(path));
+    return from_elf_with_init(path, env::InitStrategy::default());
+}
+
+/// Same as `from_elf`, initializing the program with |init|.
+pub fn from_elf_with_init(path: &Path,
+                          init: env::InitStrategy)
+                          -> Result<emu_engine::EmuEngine, loader::Error> {
+    let mut vmstate = try!(self::loader::elf::load(path));
+    vmstate.init_strategy = init;
     let emu = try!(emu_engine::EmuEngine::new(vmstate));
     return Ok(emu);
 }
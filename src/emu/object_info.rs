@@ -1,5 +1,6 @@
 use elf::types::Symbol;
-use std::collections::HashMap;
+use emu::arch::Arch;
+use std::collections::{BTreeMap, HashMap};
 use unicorn;
 
 pub type MemFlags = unicorn::unicorn_const::Protection;
@@ -12,16 +13,73 @@ pub struct MemMap {
     pub flags: MemFlags,
 }
 
+/// TlsTemplate is the initial image of the program's thread-local storage.
+#[derive(Clone, Debug)]
+pub struct TlsTemplate {
+    pub addr: u64,
+    /// Size of the initialized data at |addr|, the rest is zeroed.
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub align: u64,
+}
+
 pub struct ObjectInfo {
+    /// Entry point of the program.
+    pub entry: u64,
+    pub tls: Option<TlsTemplate>,
+    /// Whether the binary passes floating point numbers in the VFP
+    /// registers, as with the ARM hard-float ABI.
+    pub hard_float: bool,
     pub mem_maps: HashMap<String, MemMap>,
     pub symbols: HashMap<String, Symbol>,
+    /// Symbols name by address. When many symbols share an address, the
+    /// smallest name is kept so lookups don't depend on the loading order.
+    symbols_by_addr: BTreeMap<u64, String>,
 }
 
 impl ObjectInfo {
     pub fn new() -> ObjectInfo {
         return ObjectInfo {
+            entry: 0,
+            tls: None,
+            hard_float: false,
             mem_maps: HashMap::default(),
             symbols: HashMap::default(),
+            symbols_by_addr: BTreeMap::default(),
         };
     }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        if symbol.value != 0 && !symbol.name.is_empty() {
+            let name = self.symbols_by_addr
+                .entry(symbol.value)
+                .or_insert(symbol.name.clone());
+            if symbol.name < *name {
+                *name = symbol.name.clone();
+            }
+        }
+        self.symbols.insert(symbol.name.clone(), symbol);
+    }
+
+    /// Returns the mapping containing |addr|, if any.
+    pub fn mem_map_at(&self, addr: u64) -> Option<&MemMap> {
+        return self.mem_maps
+            .values()
+            .find(|m| addr >= m.addr && addr < m.addr + m.size as u64);
+    }
+
+    /// Returns the name of the symbol at |addr|, if any.
+    pub fn symbol_at(&self, addr: u64) -> Option<&str> {
+        return self.symbols_by_addr.get(&addr).map(|s| s.as_str());
+    }
+
+    /// Returns the name of the function whose code starts at |addr|, if any.
+    /// Unlike `symbol_at`, Thumb functions are found from their code address.
+    pub fn function_at(&self, arch: Arch, addr: u64) -> Option<&str> {
+        return self.symbol_at(addr).or_else(|| if arch == Arch::Arm {
+            self.symbol_at(addr | 1)
+        } else {
+            None
+        });
+    }
 }
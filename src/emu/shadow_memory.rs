@@ -0,0 +1,128 @@
+use emu;
+use emu::Error;
+use emu::dirty_pages::PAGE_SIZE;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use unicorn;
+use unicorn::unicorn_const::MemHookType;
+
+/// Regions whose content is only meaningful once written during the call.
+const SHADOWED: [(&'static str, u64, usize); 2] =
+    [("[stack]", emu::STACK_ADDR, emu::STACK_SIZE),
+     ("[emu]", emu::EMUDATA_ADDR, emu::EMUDATA_MAX_SIZE)];
+
+/// UninitRead is a read of memory not initialized by the call.
+#[derive(Clone, Debug)]
+pub struct UninitRead {
+    pub addr: u64,
+    pub size: usize,
+    /// Name of the region read.
+    pub map: String,
+}
+
+#[derive(Default)]
+struct ShadowState {
+    /// Bitmap of the initialized bytes of each page, by page address.
+    pages: HashMap<u64, Vec<u64>>,
+    reads: Vec<UninitRead>,
+}
+
+impl ShadowState {
+    fn is_initialized(&self, addr: u64) -> bool {
+        let page = addr & !(PAGE_SIZE - 1);
+        let offset = addr - page;
+        return match self.pages.get(&page) {
+            Some(bits) => {
+                bits[(offset / 64) as usize] & (1 << (offset % 64)) != 0
+            }
+            None => false,
+        };
+    }
+}
+
+/// ShadowMemory tracks the bytes of the stack and arguments data initialized
+/// during a call, and records the reads of the others.
+///
+/// Every byte is uninitialized on reset. A byte is initialized once written,
+/// by the emulated code or through the engine, such as the arguments and the
+/// syscall results. Its content before that, zeros or leftovers of the
+/// snapshot, is meaningless to the call.
+#[derive(Clone)]
+pub struct ShadowMemory {
+    state: Rc<RefCell<ShadowState>>,
+}
+
+impl ShadowMemory {
+    pub fn new() -> ShadowMemory {
+        return ShadowMemory { state: Default::default() };
+    }
+
+    /// Check the reads |engine| emulates in the shadowed regions.
+    pub fn attach(&self,
+                  engine: &Rc<RefCell<unicorn::Unicorn>>)
+                  -> Result<(), Error> {
+        for &(name, addr, size) in &SHADOWED {
+            let shadow = self.clone();
+            try!(engine.borrow_mut()
+                .add_mem_hook(MemHookType::MEM_READ,
+                              addr,
+                              addr + size as u64 - 1,
+                              move |_, _, address, size, _| {
+                                  shadow.check(name, address, size);
+                                  return true;
+                              }));
+        }
+        return Ok(());
+    }
+
+    /// Mark the bytes of [addr, addr + size) as initialized.
+    pub fn mark(&self, addr: u64, size: usize) {
+        if !is_shadowed(addr, size) {
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        for a in addr..addr + size as u64 {
+            let page = a & !(PAGE_SIZE - 1);
+            let bits = state.pages
+                .entry(page)
+                .or_insert_with(|| vec![0; (PAGE_SIZE / 64) as usize]);
+            let offset = a - page;
+            bits[(offset / 64) as usize] |= 1 << (offset % 64);
+        }
+    }
+
+    /// Forget the initialized bytes and the recorded reads.
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.pages.clear();
+        state.reads.clear();
+    }
+
+    /// Reads of uninitialized bytes since the last reset, in order.
+    pub fn uninit_reads(&self) -> Vec<UninitRead> {
+        return self.state.borrow().reads.clone();
+    }
+
+    /// Record the read of [addr, addr + size) if any of its bytes is
+    /// uninitialized.
+    fn check(&self, name: &str, addr: u64, size: usize) {
+        let mut state = self.state.borrow_mut();
+        let initialized = (addr..addr + size as u64)
+            .all(|a| state.is_initialized(a));
+        if !initialized {
+            state.reads.push(UninitRead {
+                addr: addr,
+                size: size,
+                map: String::from(name),
+            });
+        }
+    }
+}
+
+/// Whether [addr, addr + size) overlaps a shadowed region.
+fn is_shadowed(addr: u64, size: usize) -> bool {
+    let end = addr + size as u64;
+    return SHADOWED.iter()
+        .any(|&(_, start, len)| addr < start + len as u64 && end > start);
+}
@@ -1,13 +1,20 @@
 use byteorder::{ByteOrder, LittleEndian};
 use emu;
 use emu::Error;
+use emu::arch::{Arch, Endian};
+use emu::dirty_pages::{DirtyPages, PAGE_SIZE};
 use emu::env;
 use emu::env::Kernel;
-use emu::args::PushableArgs;
+use emu::args::{PushableArgs, ReturnType};
 use emu::emu_engine::EmuEffects;
-use emu::env::Env;
+use emu::env::{Env, InitStrategy};
+use emu::call_tracer::CallEdge;
+use emu::fault::Fault;
+use emu::guard_pages::OutOfBounds;
+use emu::heap_tracker::HeapEffects;
+use emu::mem_tracker::MemWrite;
 use emu::object_info::{MemMap, ObjectInfo};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use unicorn;
 use unicorn::unicorn_const::{PROT_EXEC, PROT_READ, PROT_WRITE};
@@ -16,33 +23,49 @@ use utils::LogError;
 
 pub struct VmState {
     pub engine: Rc<RefCell<unicorn::Unicorn>>,
+    pub arch: Arch,
     pub object_info: ObjectInfo,
     pub stack_info: Option<MemMap>,
     pub emudata_info: Option<MemMap>,
+    /// Size of the emudata mapped past `emudata_info`, as the arguments
+    /// needed it.
+    emudata_growth: Cell<usize>,
     pub shellcode_info: Option<MemMap>,
     pub snapshot: Vec<(MemMap, Vec<u8>)>,
+    /// Pages written since the snapshot was taken or restored.
+    pub dirty_pages: DirtyPages,
     pub kernel: Option<Rc<RefCell<Kernel>>>,
+    /// How the program is initialized once loaded.
+    pub init_strategy: InitStrategy,
 }
 
 pub struct DataWriter<'a> {
     write_ptr: u64,
+    /// Address the data can't grow past.
+    limit: u64,
     vmstate: &'a VmState,
 }
 
 impl VmState {
-    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>) -> VmState {
+    pub fn new(engine: Rc<RefCell<unicorn::Unicorn>>, arch: Arch) -> VmState {
         return VmState {
             engine: engine,
+            arch: arch,
             object_info: ObjectInfo::new(),
             stack_info: None,
             emudata_info: None,
+            emudata_growth: Cell::new(0),
             shellcode_info: None,
             snapshot: Default::default(),
+            dirty_pages: DirtyPages::new(),
             kernel: Default::default(),
+            init_strategy: InitStrategy::default(),
         };
     }
 
     pub fn init(&mut self) -> Result<(), Error> {
+        try!(self.dirty_pages.attach(&self.engine));
+
         // Init the stack.
         let stack_info = MemMap {
             addr: emu::STACK_ADDR,
@@ -103,23 +126,67 @@ impl VmState {
                 .mem_read(map.addr, map.size));
             self.snapshot.push((map.clone(), mem));
         }
+        self.dirty_pages.take();
         Ok(())
     }
 
+    /// Restore the pages written since the snapshot.
     pub fn restore_snapshot(&self) -> Result<(), Error> {
-        for &(ref map, ref data) in &self.snapshot {
-            try!(self.engine.borrow().mem_write(map.addr, data))
+        let engine = self.engine.borrow();
+        for page in self.dirty_pages.take() {
+            for &(ref map, ref data) in &self.snapshot {
+                let map_end = map.addr + map.size as u64;
+                if page < map.addr || page >= map_end {
+                    continue;
+                }
+                let start = (page - map.addr) as usize;
+                let end = ::std::cmp::min(start + PAGE_SIZE as usize,
+                                          data.len());
+                try!(engine.mem_write(page, &data[start..end]));
+                break;
+            }
         }
         Ok(())
     }
 
-    pub fn emudata_writer<'a>(&'a self) -> Result<DataWriter<'a>, Error> {
+    /// Writer of at most |max_size| bytes of arguments data.
+    pub fn emudata_writer<'a>(&'a self,
+                              max_size: usize)
+                              -> Result<DataWriter<'a>, Error> {
         if let Some(ref emudata) = self.emudata_info {
-            return Ok(DataWriter::new(self, emudata.addr));
+            let max_size = ::std::cmp::min(max_size, emu::EMUDATA_MAX_SIZE);
+            return Ok(DataWriter::new(self,
+                                      emudata.addr,
+                                      emudata.addr + max_size as u64));
         }
         return Err(Error::EmuDataUninitialized);
     }
 
+    /// Map the emudata up to |end|, if it isn't already. Writes starting
+    /// out of the emudata, such as to the kernel data, are left alone.
+    fn grow_emudata(&self, start: u64, end: u64) -> Result<(), Error> {
+        let emudata = match self.emudata_info {
+            Some(ref emudata) => emudata,
+            None => return Err(Error::EmuDataUninitialized),
+        };
+        if start < emudata.addr ||
+           start >= emudata.addr + emu::EMUDATA_MAX_SIZE as u64 {
+            return Ok(());
+        }
+        let mapped_end = emudata.addr +
+                         (emudata.size + self.emudata_growth.get()) as u64;
+        if end <= mapped_end {
+            return Ok(());
+        }
+
+        let size = (end - mapped_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        try!(self.engine
+            .borrow()
+            .mem_map(mapped_end, size as usize, PROT_READ | PROT_WRITE));
+        self.emudata_growth.set(self.emudata_growth.get() + size as usize);
+        return Ok(());
+    }
+
     pub fn base_sp(&self) -> Option<u64> {
         return match self.stack_info {
             Some(ref s) => Some(s.addr + s.size as u64),
@@ -128,96 +195,184 @@ impl VmState {
     }
 
     pub fn sp(&self) -> Result<u64, Error> {
-        // TODO: Make it arch dependant.
         return self.engine
             .borrow()
-            .reg_read(RegEnum::RSP as i32)
+            .reg_read(self.arch.sp_reg())
             .map_err(|e| Error::UnicornError(e));
     }
 
     pub fn set_sp(&self, value: u64) -> Result<(), Error> {
-        // TODO: Make it arch dependant.
         return self.engine
             .borrow()
-            .reg_write(RegEnum::RSP as i32, value)
+            .reg_write(self.arch.sp_reg(), value)
             .map_err(|e| Error::UnicornError(e));
     }
 
     pub fn ip(&self) -> Result<u64, Error> {
-        // TODO: Make it arch dependant.
         return self.engine
             .borrow()
-            .reg_read(RegEnum::RIP as i32)
+            .reg_read(self.arch.ip_reg())
             .map_err(|e| Error::UnicornError(e));
     }
 
     pub fn set_ip(&self, value: u64) -> Result<(), Error> {
-        // TODO: Make it arch dependant.
         return self.engine
             .borrow()
-            .reg_write(RegEnum::RIP as i32, value)
+            .reg_write(self.arch.ip_reg(), value)
             .map_err(|e| Error::UnicornError(e));
     }
 
+    /// Set sp back to the base of the stack. The stack content itself is
+    /// restored with the snapshot.
     pub fn reset_stack(&self) -> Result<(), Error> {
-        if let Some(ref stack_info) = self.stack_info {
-            let base_sp = self.base_sp().unwrap();
+        if let Some(base_sp) = self.base_sp() {
             try!(self.set_sp(base_sp)
                 .log_err(|_| {
                     String::from("Failed to set sp to base of stack")
                 }));
-            let mut init_data: Vec<u8> = Vec::new();
-            init_data.resize(stack_info.size, 0);
-            try!(self.engine.borrow().mem_write(stack_info.addr, &init_data));
             return Ok(());
         }
         return Err(Error::StackUninitialized);
     }
 
 
-    pub fn reset_emudata(&self) -> Result<(), Error> {
-        if let Some(ref emudata_info) = self.emudata_info {
-            let mut init_data: Vec<u8> = Vec::new();
-            init_data.resize(emudata_info.size, 0);
-            try!(self.engine.borrow().mem_write(emudata_info.addr, &init_data));
-            return Ok(());
+    /// Unmap the emudata grown past its initial size. The initial emudata
+    /// is restored with the snapshot.
+    pub fn reset_emudata(&mut self) -> Result<(), Error> {
+        let (addr, size) = match self.emudata_info {
+            Some(ref emudata) => (emudata.addr, emudata.size),
+            None => return Err(Error::EmuDataUninitialized),
+        };
+        let growth = self.emudata_growth.get();
+        if growth > 0 {
+            try!(self.engine
+                .borrow()
+                .mem_unmap(addr + size as u64, growth));
+            self.emudata_growth.set(0);
         }
-        return Err(Error::StackUninitialized);
+        self.sync_emudata_map();
+        return Ok(());
     }
 
+    /// Extend the `[emu]` mapping over the emudata grown by the arguments,
+    /// so that accesses to it are reported against the emudata.
+    pub fn sync_emudata_map(&mut self) {
+        if let Some(ref emudata) = self.emudata_info {
+            let growth = self.emudata_growth.get();
+            let maps = &mut self.object_info.mem_maps;
+            if let Some(map) = maps.get_mut(&emudata.name) {
+                map.size = emudata.size + growth;
+            }
+        }
+    }
 
     pub fn return_value(&self) -> Result<u64, Error> {
         return self.engine
             .borrow()
-            .reg_read(RegEnum::RAX as i32)
+            .reg_read(self.arch.return_reg())
             .map_err(|e| Error::UnicornError(e));
     }
 
-    pub fn stack_push(&self, value: u64) -> Result<(), Error> {
-        let sp = try!(self.sp());
-        // TODO: Make it arch dependant.
-        try!(self.set_sp(sp - 8));
-        return self.engine
+    /// Set the low quadword of the |n|th xmm register to |value|. The xmm
+    /// registers are set through a shellcode since the engine only handles
+    /// 64 bits registers.
+    pub fn set_xmm(&self, n: u8, value: u64) -> Result<(), Error> {
+        if self.arch != Arch::X86_64 {
+            return Err(Error::NotImplemented);
+        }
+        let saved_rax = try!(self.engine
             .borrow()
-            .mem_write(sp - 8, &self.native_pack(value))
-            .map_err(|e| Error::UnicornError(e));
+            .reg_read(RegEnum::RAX as i32));
+        // mov rax, value
+        let mut shellcode = vec![0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0];
+        LittleEndian::write_u64(&mut shellcode[2..], value);
+        // movq xmm<n>, rax
+        shellcode.extend(&[0x66, 0x48, 0x0F, 0x6E, 0xC0 | (n << 3)]);
+        try!(self.run_shellcode(&shellcode));
+        try!(self.engine.borrow().reg_write(RegEnum::RAX as i32, saved_rax));
+        return Ok(());
+    }
+
+    /// Returns the low quadword of the |n|th xmm register.
+    pub fn xmm(&self, n: u8) -> Result<u64, Error> {
+        if self.arch != Arch::X86_64 {
+            return Err(Error::NotImplemented);
+        }
+        let saved_rax = try!(self.engine
+            .borrow()
+            .reg_read(RegEnum::RAX as i32));
+        // movq rax, xmm<n>
+        try!(self.run_shellcode(&[0x66, 0x48, 0x0F, 0x7E, 0xC0 | (n << 3)]));
+        let value = try!(self.engine.borrow().reg_read(RegEnum::RAX as i32));
+        try!(self.engine.borrow().reg_write(RegEnum::RAX as i32, saved_rax));
+        return Ok(value);
+    }
+
+    /// Returns the x87 st(0) register as a double, on x86.
+    pub fn st0(&self) -> Result<u64, Error> {
+        if self.arch != Arch::X86 {
+            return Err(Error::NotImplemented);
+        }
+        let addr = self.shellcode_info.as_ref().unwrap().addr;
+        let value_addr = addr + emu::SHELLCODE_SIZE as u64 - 8;
+        // fst qword ptr [value_addr]
+        let mut shellcode = vec![0xDD, 0x15, 0, 0, 0, 0];
+        LittleEndian::write_u32(&mut shellcode[2..], value_addr as u32);
+        try!(self.run_shellcode(&shellcode));
+        let value = try!(self.engine.borrow().mem_read(value_addr, 8));
+        return Ok(LittleEndian::read_u64(&value));
+    }
+
+    pub fn stack_push(&self, value: u64) -> Result<(), Error> {
+        let sp = try!(self.sp()) - self.arch.pointer_size() as u64;
+        try!(self.set_sp(sp));
+        return self.mem_write(sp, &self.arch.pack(value));
     }
 
     /// Set the emulator state's return value.
     pub fn set_call_return(&self, return_va: u64) -> Result<(), Error> {
-        // TODO: Need to make arch dependant?
-        try!(self.stack_push(return_va));
+        match self.arch.link_reg() {
+            Some(reg) => try!(self.engine.borrow().reg_write(reg, return_va)),
+            None => try!(self.stack_push(return_va)),
+        }
         return Ok(());
     }
 
     pub fn collect_call_results(&self,
-                                args: PushableArgs)
+                                args: PushableArgs,
+                                writes: Vec<MemWrite>,
+                                heap: HeapEffects,
+                                calls: Vec<CallEdge>,
+                                out_of_bounds: Vec<OutOfBounds>,
+                                fault: Option<Fault>)
                                 -> Result<EmuEffects, Error> {
         let return_value = try!(self.return_value());
+        let return_high = match args.return_type() {
+            ReturnType::Pair => {
+                Some(try!(self.engine
+                    .borrow()
+                    .reg_read(self.arch.return_high_reg())))
+            }
+            _ => None,
+        };
+        let returned = args.sret();
+        let syscalls = match self.kernel {
+            Some(ref kernel) => kernel.borrow().syscalls(),
+            None => Vec::new(),
+        };
         return Ok(EmuEffects {
             vmstate: self,
             return_value: return_value,
+            return_high: return_high,
+            returned: returned,
             args: args,
+            writes: writes,
+            syscalls: syscalls,
+            heap: heap,
+            calls: calls,
+            out_of_bounds: out_of_bounds,
+            uninit_reads: self.dirty_pages.shadow().uninit_reads(),
+            fault: fault,
         });
     }
 
@@ -240,15 +395,24 @@ impl VmState {
     pub fn write_str(&self, addr: u64, data: &str) -> Result<u64, Error> {
         let mut data_buf = data.as_bytes().to_vec();
         data_buf.push(0);
-        try!(self.engine.borrow().mem_write(addr, &data_buf));
+        try!(self.mem_write(addr, &data_buf));
         return Ok(addr + data_buf.len() as u64);
     }
 
     pub fn read_usize(&self, addr: u64) -> Result<u64, Error> {
-        // TODO: Make it arch independant.
-        return Ok(LittleEndian::read_u64(&try!(self.engine
+        let data = try!(self.engine
             .borrow()
-            .mem_read(addr, 8))));
+            .mem_read(addr, self.arch.pointer_size()));
+        return Ok(self.arch.unpack(&data));
+    }
+
+    /// Write |data| at |addr| and mark its pages as dirty. Memory must be
+    /// written with this function rather than the engine so it gets restored
+    /// between calls.
+    pub fn mem_write(&self, addr: u64, data: &[u8]) -> Result<(), Error> {
+        try!(self.engine.borrow().mem_write(addr, data));
+        self.dirty_pages.mark(addr, data.len());
+        return Ok(());
     }
 
     /// Unlike unicorn.mem_map, this function keep track of the mapping
@@ -273,7 +437,7 @@ impl VmState {
 
     pub fn run_shellcode(&self, code: &[u8]) -> Result<(), Error> {
         let addr = self.shellcode_info.as_ref().unwrap().addr;
-        try!(self.engine.borrow().mem_write(addr, code));
+        try!(self.mem_write(addr, code));
         try!(self.engine.borrow().emu_start(addr,
                                             addr + code.len() as u64,
                                             emu::EMU_TIMEOUT,
@@ -281,42 +445,79 @@ impl VmState {
 
         return Ok(());
     }
-
-    fn native_pack(&self, n: u64) -> Vec<u8> {
-        // TODO: Make it arch dependant.
-        let mut packed = [0; 8];
-        LittleEndian::write_u64(&mut packed, n);
-        return packed.to_vec();
-    }
 }
 
 impl<'a> DataWriter<'a> {
-    pub fn new(vmstate: &'a VmState, write_ptr: u64) -> DataWriter<'a> {
+    pub fn new(vmstate: &'a VmState,
+               write_ptr: u64,
+               limit: u64)
+               -> DataWriter<'a> {
         return DataWriter {
             write_ptr: write_ptr,
+            limit: limit,
             vmstate: vmstate,
         };
     }
 
+    /// Byte order of the written data.
+    pub fn endian(&self) -> Endian {
+        return self.vmstate.arch.endian();
+    }
+
+    /// Make room for |size| more bytes, growing the emudata as needed.
+    fn reserve(&mut self, size: usize) -> Result<(), Error> {
+        let end = self.write_ptr + size as u64;
+        if end > self.limit {
+            return Err(Error::EmuDataOverflow);
+        }
+        return self.vmstate.grow_emudata(self.write_ptr, end);
+    }
+
+    /// Skip up to a multiple of |align|. The skipped bytes are left
+    /// uninitialized.
+    pub fn align(&mut self, align: u64) -> Result<(), Error> {
+        let padding = (align - self.write_ptr % align) % align;
+        try!(self.reserve(padding as usize));
+        self.write_ptr += padding;
+        return Ok(());
+    }
+
     pub fn write_str(&mut self, data: &str) -> Result<u64, Error> {
+        try!(self.reserve(data.len() + 1));
         let str_ptr = self.write_ptr;
         self.write_ptr = try!(self.vmstate.write_str(self.write_ptr, data));
         return Ok(str_ptr);
     }
 
     pub fn write_data(&mut self, data: &[u8]) -> Result<u64, Error> {
+        try!(self.reserve(data.len()));
         let data_ptr = self.write_ptr;
-        try!(self.vmstate.engine.borrow().mem_write(self.write_ptr, &data));
+        try!(self.vmstate.mem_write(self.write_ptr, &data));
         self.write_ptr += data.len() as u64;
         return Ok(data_ptr);
     }
 
     pub fn write_usize(&mut self, value: u64) -> Result<u64, Error> {
-        let data = self.vmstate.native_pack(value);
+        let data = self.vmstate.arch.pack(value);
         return self.write_data(&data);
     }
 
     pub fn current_ptr(&self) -> u64 {
         return self.write_ptr;
     }
+
+    /// Move the writer to |ptr|, such as to write over data again.
+    pub fn seek(&mut self, ptr: u64) {
+        self.write_ptr = ptr;
+    }
+
+    /// Skip to the next page boundary and keep the page past it as a guard
+    /// page. Returns the guard page's address.
+    pub fn guard_page(&mut self) -> Result<u64, Error> {
+        let page = (self.write_ptr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        self.write_ptr = page;
+        try!(self.reserve(PAGE_SIZE as usize));
+        self.write_ptr = page + PAGE_SIZE;
+        return Ok(page);
+    }
 }
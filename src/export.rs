@@ -0,0 +1,208 @@
+use report::FunctionReport;
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+
+/// Disassembler for which a script is exported. The script renames each
+/// identified function after its best candidate and comments it with every
+/// matched candidates and their confidence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Script {
+    /// IDA IDC script.
+    Idc,
+    /// IDAPython script.
+    IdaPython,
+    /// Ghidra Python script.
+    Ghidra,
+    /// radare2 commands file, to be run with `r2 -i`.
+    R2,
+}
+
+/// Names given by a script so far. Functions matching the same candidate
+/// get a numbered suffix, as a second rename to the same name would fail.
+#[derive(Default)]
+pub struct Names {
+    given: HashSet<String>,
+}
+
+impl Names {
+    pub fn new() -> Names {
+        return Default::default();
+    }
+
+    /// Unused name derived from |name|, reserved from now on.
+    fn unique(&mut self, name: String) -> String {
+        let mut unique = name.clone();
+        let mut n = 1;
+        while self.given.contains(&unique) {
+            unique = format!("{}_{}", name, n);
+            n += 1;
+        }
+        self.given.insert(unique.clone());
+        return unique;
+    }
+}
+
+pub fn begin(script: Script, out: &mut Write) -> io::Result<()> {
+    match script {
+        Script::Idc => {
+            try!(writeln!(out, "#include <idc.idc>"));
+            try!(writeln!(out, ""));
+            try!(writeln!(out, "static dirt_rename(ea, name, comment) {{"));
+            try!(writeln!(out, "  add_func(ea, BADADDR);"));
+            try!(writeln!(out,
+                          "  set_name(ea, name, SN_NOWARN | SN_NOCHECK);"));
+            try!(writeln!(out, "  set_func_cmt(ea, comment, 1);"));
+            try!(writeln!(out, "}}"));
+            try!(writeln!(out, ""));
+            writeln!(out, "static main() {{")
+        }
+        Script::IdaPython => {
+            try!(writeln!(out, "import ida_funcs"));
+            try!(writeln!(out, "import idc"));
+            try!(writeln!(out, ""));
+            try!(writeln!(out, ""));
+            try!(writeln!(out, "def dirt_rename(ea, name, comment):"));
+            try!(writeln!(out, "    ida_funcs.add_func(ea)"));
+            try!(writeln!(out,
+                          "    idc.set_name(ea, name, idc.SN_NOWARN | \
+                           idc.SN_NOCHECK)"));
+            try!(writeln!(out, "    idc.set_func_cmt(ea, comment, 1)"));
+            writeln!(out, "")
+        }
+        Script::Ghidra => {
+            try!(writeln!(out, "# Rename the functions identified by DIRT."));
+            try!(writeln!(out, "# @category DIRT"));
+            try!(writeln!(out,
+                          "from ghidra.program.model.symbol import \
+                           SourceType"));
+            try!(writeln!(out, ""));
+            try!(writeln!(out, ""));
+            try!(writeln!(out, "def dirt_rename(ea, name, comment):"));
+            try!(writeln!(out, "    addr = toAddr(ea)"));
+            try!(writeln!(out, "    func = getFunctionAt(addr)"));
+            try!(writeln!(out, "    if func is None:"));
+            try!(writeln!(out, "        func = createFunction(addr, None)"));
+            try!(writeln!(out, "    if func is None:"));
+            try!(writeln!(out, "        return"));
+            try!(writeln!(out,
+                          "    func.setName(name, SourceType.USER_DEFINED)"));
+            try!(writeln!(out, "    func.setComment(comment)"));
+            writeln!(out, "")
+        }
+        Script::R2 => Ok(()),
+    }
+}
+
+pub fn record(script: Script,
+              out: &mut Write,
+              names: &mut Names,
+              report: &FunctionReport)
+              -> io::Result<()> {
+    let best = match report.matches.first() {
+        Some(best) => best,
+        None => return Ok(()),
+    };
+    let name = names.unique(identifier(&best.name));
+    let comment = comment(report);
+
+    match script {
+        Script::Idc => {
+            writeln!(out,
+                     "  dirt_rename(0x{:x}, \"{}\", \"{}\");",
+                     report.address,
+                     escape(&name),
+                     escape(&comment))
+        }
+        Script::IdaPython | Script::Ghidra => {
+            writeln!(out,
+                     "dirt_rename(0x{:x}, \"{}\", \"{}\")",
+                     report.address,
+                     escape(&name),
+                     escape(&comment))
+        }
+        Script::R2 => {
+            // The name is an identifier, safe as is, but the comment must
+            // not hold any r2 command separator, redirection or the like.
+            try!(writeln!(out, "af @ 0x{:x}", report.address));
+            try!(writeln!(out, "afn {} 0x{:x}", name, report.address));
+            writeln!(out,
+                     "CC {} @ 0x{:x}",
+                     r2_safe(&comment),
+                     report.address)
+        }
+    }
+}
+
+pub fn finish(script: Script, out: &mut Write) -> io::Result<()> {
+    match script {
+        Script::Idc => writeln!(out, "}}"),
+        _ => Ok(()),
+    }
+}
+
+/// Comment listing the candidates matched by a function.
+fn comment(report: &FunctionReport) -> String {
+    let candidates = report.matches
+        .iter()
+        .map(|m| format!("{} ({:.0}%)", m.name, m.confidence * 100.0))
+        .collect::<Vec<String>>()
+        .join(", ");
+    return format!("DIRT: {}", candidates);
+}
+
+/// Turn a candidate name into a valid symbol name, e.g.
+/// `std::string::resize(n, c)` into `std_string_resize_n_c`.
+fn identifier(name: &str) -> String {
+    let mut ident = String::with_capacity(name.len());
+    for c in name.chars() {
+        if is_ident_char(c) {
+            ident.push(c);
+        } else if !ident.ends_with('_') {
+            ident.push('_');
+        }
+    }
+    let ident = String::from(ident.trim_matches('_'));
+    if ident.is_empty() ||
+       ident.chars().next().map(|c| c.is_digit(10)).unwrap_or(false) {
+        return format!("_{}", ident);
+    }
+    return ident;
+}
+
+fn is_ident_char(c: char) -> bool {
+    return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') ||
+           (c >= '0' && c <= '9') || c == '_';
+}
+
+/// Replace the characters of |s| an r2 command could interpret, such as
+/// `;`, `|`, `>`, `@`, backticks or newlines, with `_`.
+fn r2_safe(s: &str) -> String {
+    return s.chars()
+        .map(|c| if is_ident_char(c) || " ,.:()%+-=[]{}/".contains(c) {
+            c
+        } else {
+            '_'
+        })
+        .collect();
+}
+
+/// Escape a string to be embedded in a double quoted IDC or Python string.
+/// Control characters, which would break the line, are escaped too.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                escaped.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
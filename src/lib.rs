@@ -6,8 +6,11 @@ extern crate lua;
 extern crate rand;
 extern crate unicorn;
 
+pub mod cache;
 pub mod dirt_engine;
 pub mod emu;
+pub mod export;
+pub mod report;
 pub mod rules;
 pub mod utils;
 pub mod bin;
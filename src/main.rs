@@ -1,63 +1,560 @@
 extern crate dirt;
+extern crate getopts;
 extern crate pbr;
 
 use pbr::ProgressBar;
 
 use dirt::bin::cppfilt;
-use dirt::bin::bin_file;
+use dirt::bin::bin_file::{self, BinFile, Symbol};
+use dirt::cache::Cache;
 use dirt::emu;
+use dirt::emu::EmuLimits;
+use dirt::emu::args::EmuArgs;
+use dirt::emu::call_tracer::EdgeKind;
+use dirt::emu::datatypes::{DataType, IntegerData, StringData};
+use dirt::emu::env::InitStrategy;
 use dirt::rules;
-use dirt::dirt_engine::{DirtEngine, TargetInfo};
-use std::path::Path;
+use dirt::dirt_engine;
+use dirt::dirt_engine::{CallingConvention, DEFAULT_MIN_CONFIDENCE, DirtEngine,
+                        FunctionInfo, RuleOutcome, TargetInfo,
+                        identify_parallel};
+use dirt::report::{Format, FunctionReport, Reporter};
+use getopts::{Matches, Options};
 use std::env;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+const USAGE: &'static str = "Usage: dirt COMMAND [options] TARGET [ARGS...]
+
+Commands:
+    identify    Identify the functions of TARGET
+    call        Emulate a function of TARGET with ARGS and print its result
+    eval        Print the outcome of every rule against a function of TARGET
+    list-rules  List the loaded candidates and their number of rules
+
+ARGS starting with a dash, such as negative integers, go after --:
+    dirt call -F FUNCTION TARGET -- -1";
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("r",
+                "rules",
+                "directory to load the rules from (default: ./rules)",
+                "DIR");
+    opts.optopt("f", "filter", "only load the rules of CANDIDATE", "CANDIDATE");
+    opts.optmulti("F",
+                  "function",
+                  "function to work on, by symbol name or address, \
+                   optionally with its calling convention",
+                  "NAME|ADDR[@CC]");
+    opts.optopt("",
+                "cc",
+                "calling convention of the functions: systemv, cdecl, \
+                 stdcall, fastcall, thiscall, win64, aapcs, aapcs-vfp, \
+                 aapcs64, o32 (default: per architecture)",
+                "CC");
+    opts.optopt("o", "output", "write the results to FILE", "FILE");
+    opts.optopt("",
+                "format",
+                "identification report format: text, json, csv, or a \
+                 renaming script for idc, idapython, ghidra, r2",
+                "FORMAT");
+    opts.optopt("",
+                "min-confidence",
+                "minimum ratio of a candidate's rules a function must \
+                 verify to be reported, from 0 to 1 (default: 1)",
+                "RATIO");
+    opts.optopt("",
+                "timeout",
+                "give up on the emulated calls after MS milliseconds, 0 for \
+                 no timeout (default: 1000)",
+                "MS");
+    opts.optopt("",
+                "max-insns",
+                "give up on the emulated calls after N instructions, 0 for \
+                 no limit (default: 65536)",
+                "N");
+    opts.optopt("",
+                "max-data",
+                "maximum size of the arguments given to the emulated calls, \
+                 up to 16777216 bytes (default: 16777216)",
+                "BYTES");
+    opts.optopt("",
+                "init",
+                "how to initialize the program: main, none, or the address \
+                 or symbol to run the entry point up to (default: main)",
+                "STRATEGY");
+    opts.optopt("",
+                "cache",
+                "reuse and store the identification results in FILE",
+                "FILE");
+    opts.optopt("j",
+                "jobs",
+                "number of worker threads identifying functions",
+                "N");
+    opts.optflag("",
+                 "calls",
+                 "print the call tree of the function emulated by call");
+    opts.optflag("d", "debug", "trace the emulated instructions");
+    opts.optflag("h", "help", "print this help");
+    return opts;
+}
+
+fn fail(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", msg);
+    process::exit(1);
+}
+
+/// Print the usage, on stderr when exiting with a failure |code|.
+fn usage(opts: &Options, code: i32) -> ! {
+    if code == 0 {
+        println!("{}", opts.usage(USAGE));
+    } else {
+        let _ = writeln!(io::stderr(), "{}", opts.usage(USAGE));
+    }
+    process::exit(code);
+}
 
 pub fn main() {
-    let target_opt = env::args().nth(1);
-    if target_opt.is_none() {
-        println!("Usage: dirt TARGET");
-        return;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = options();
+    let command = match args.first() {
+        Some(command) => command.clone(),
+        None => usage(&opts, 1),
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => fail(&e.to_string()),
+    };
+    if matches.opt_present("h") {
+        usage(&opts, 0);
+    }
+
+    match command.as_str() {
+        "identify" => cmd_identify(&matches),
+        "call" => cmd_call(&matches),
+        "eval" => cmd_eval(&matches),
+        "list-rules" => cmd_list_rules(&matches),
+        "-h" | "--help" | "help" => usage(&opts, 0),
+        _ => fail(&format!("Unknown command: {}\n\n{}", command, USAGE)),
     }
+}
+
+fn rules_dir(matches: &Matches) -> String {
+    return matches.opt_str("r").unwrap_or(String::from("./rules"));
+}
 
-    let target = target_opt.unwrap();
+/// Load the ruleset from the --rules directory, with the --filter applied.
+fn load_rules(matches: &Matches) -> Box<rules::RuleSet> {
+    return rules::load_all(Path::new(&rules_dir(matches)),
+                           matches.opt_str("f"));
+}
+
+/// Load the target binary and create a DIRT engine for it.
+fn load_target(matches: &Matches) -> (Box<BinFile>, DirtEngine) {
+    let target = match matches.free.first() {
+        Some(target) => target.clone(),
+        None => fail("Missing TARGET"),
+    };
     let target = Path::new(&target);
     let bin = bin_file::load(target)
         .expect(&format!("Failed to load target: {}",
                          target.to_str().unwrap()));
 
     // Create the emulation engine.
-    let emu = emu::from_elf(target)
+    let emu = emu::from_elf_with_init(target, init_strategy(matches))
         .expect("Failed to create emulator from ELF");
-    // Load the ruleset.
-    let ruleset = rules::load_all(Path::new("./rules"));
     // Create the DIRT engine.
-    let mut dirt = DirtEngine::new(emu, ruleset);
+    let mut dirt = DirtEngine::new(emu, load_rules(matches));
+    dirt.set_min_confidence(min_confidence(matches));
+    dirt.emu_mut().set_limits(emu_limits(matches));
+    if matches.opt_present("d") {
+        dirt.debugger().attach().expect("Failed to attach debugger");
+    }
 
-    let funcs = bin.functions();
-    println!("Identifying {} functions", funcs.len());
+    return (bin, dirt);
+}
+
+/// Program initialization given by --init.
+fn init_strategy(matches: &Matches) -> InitStrategy {
+    return match matches.opt_str("init") {
+        Some(init) => init.parse().unwrap_or_else(|e: String| fail(&e)),
+        None => InitStrategy::default(),
+    };
+}
+
+fn min_confidence(matches: &Matches) -> f64 {
+    return match matches.opt_str("min-confidence").map(|c| c.parse::<f64>()) {
+        Some(Ok(c)) if c >= 0.0 && c <= 1.0 => c,
+        Some(_) => fail("--min-confidence must be between 0 and 1"),
+        None => DEFAULT_MIN_CONFIDENCE,
+    };
+}
+
+/// Emulation limits given by --timeout, --max-insns and --max-data.
+fn emu_limits(matches: &Matches) -> EmuLimits {
+    let mut limits = EmuLimits::default();
+    match matches.opt_str("timeout").map(|t| t.parse::<u64>()) {
+        Some(Ok(timeout)) => limits.timeout = timeout * 1000,
+        Some(Err(_)) => fail("--timeout must be a number of milliseconds"),
+        None => (),
+    }
+    match matches.opt_str("max-insns").map(|n| n.parse::<usize>()) {
+        Some(Ok(max_count)) => limits.max_count = max_count,
+        Some(Err(_)) => fail("--max-insns must be a number of instructions"),
+        None => (),
+    }
+    match matches.opt_str("max-data").map(|n| n.parse::<usize>()) {
+        Some(Ok(max_data)) if max_data <= emu::EMUDATA_MAX_SIZE => {
+            limits.max_data = max_data
+        }
+        Some(_) => fail("--max-data must be at most 16777216 bytes"),
+        None => (),
+    }
+    return limits;
+}
+
+/// Open the --output file, or stdout.
+fn output(matches: &Matches) -> Box<Write> {
+    return match matches.opt_str("o") {
+        Some(path) => {
+            Box::new(File::create(&path)
+                .expect(&format!("Failed to create output: {}", path)))
+        }
+        None => Box::new(io::stdout()),
+    };
+}
+
+fn calling_convention(matches: &Matches,
+                      dirt: &DirtEngine)
+                      -> CallingConvention {
+    return match matches.opt_str("cc") {
+        Some(cc) => cc.parse().unwrap_or_else(|e: String| fail(&e)),
+        None => dirt.default_cc(),
+    };
+}
+
+/// Parse an integer written either in decimal or in hexadecimal with a `0x`
+/// prefix.
+fn parse_int(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        return u64::from_str_radix(&s[2..], 16).ok();
+    }
+    if s.starts_with("-") {
+        return s.parse::<i64>().ok().map(|n| n as u64);
+    }
+    return s.parse().ok();
+}
+
+/// Resolve the --function values into symbols, with their calling
+/// convention. Functions given as NAME@CC use CC instead of |default_cc|.
+fn selected_functions(matches: &Matches,
+                      bin: &BinFile,
+                      default_cc: CallingConvention)
+                      -> Vec<(Symbol, CallingConvention)> {
+    return matches.opt_strs("F")
+        .iter()
+        .map(|f| {
+            let (f, cc) = match f.rfind('@') {
+                Some(i) => {
+                    match f[i + 1..].parse() {
+                        Ok(cc) => (&f[..i], cc),
+                        Err(_) => (&f[..], default_cc),
+                    }
+                }
+                None => (&f[..], default_cc),
+            };
+            if let Some(sym) = bin.get_symbol(f) {
+                return (sym, cc);
+            }
+            match parse_int(f) {
+                Some(addr) => {
+                    (Symbol {
+                        name: format!("sub_{:x}", addr),
+                        value: addr,
+                        size: 0,
+                    },
+                     cc)
+                }
+                None => fail(&format!("Function not found: {}", f)),
+            }
+        })
+        .collect();
+}
+
+fn single_function(matches: &Matches,
+                   bin: &BinFile,
+                   default_cc: CallingConvention)
+                   -> (Symbol, CallingConvention) {
+    let mut funcs = selected_functions(matches, bin, default_cc);
+    if funcs.len() != 1 {
+        fail("Expected a single --function");
+    }
+    return funcs.pop().unwrap();
+}
+
+fn cmd_identify(matches: &Matches) {
+    let format = match matches.opt_str("format") {
+        Some(f) => f.parse::<Format>().unwrap_or_else(|e| fail(&e)),
+        None => Format::Text,
+    };
+    let jobs = match matches.opt_str("j").map(|j| j.parse::<usize>()) {
+        Some(Ok(jobs)) if jobs > 0 => jobs,
+        Some(_) => fail("--jobs must be a positive integer"),
+        None => 1,
+    };
+    let (bin, mut dirt) = load_target(matches);
+    let cc = calling_convention(matches, &dirt);
+    let cache = matches.opt_str("cache").map(|path| {
+        let cache = Cache::open(Path::new(&path))
+            .unwrap_or_else(|e| fail(&format!("Failed to open cache: {}", e)));
+        Arc::new(Mutex::new(cache))
+    });
+
+    let funcs = if matches.opt_present("F") {
+        selected_functions(matches, &*bin, cc)
+    } else {
+        bin.functions().into_iter().map(|func| (func, cc)).collect()
+    };
+
+    // Structured reports written to stdout must stay free of progress
+    // output.
+    let show_progress = format == Format::Text || matches.opt_present("o");
+    if show_progress {
+        println!("Identifying {} functions", funcs.len());
+    }
     let mut pb = ProgressBar::new(funcs.len() as u64);
 
-    for func in &funcs {
-        let cc = dirt.default_cc();
-        match dirt.identify_function(&TargetInfo {
-            fva: func.value,
-            cc: cc,
-        }) {
-            Ok(matches) => {
-                if matches.len() > 0 {
-                    println!("\r\x1b[K{}: matched by {:?}",
-                             cppfilt::unmangle(&func.name),
-                             matches.iter()
-                                 .map(|m| m.name.as_str())
-                                 .collect::<Vec<&str>>());
+    let mut reporter = Reporter::new(format, output(matches));
+    reporter.begin().expect("Failed to write report");
+
+    if let Some(ref cache) = cache {
+        dirt.set_cache(cache.clone());
+    }
+    if jobs > 1 {
+        // This engine is the calling thread's worker, the other workers load
+        // their own.
+        let target = matches.free[0].clone();
+        let rules_path = rules_dir(matches);
+        let filter = matches.opt_str("f");
+        let min_confidence = min_confidence(matches);
+        let limits = emu_limits(matches);
+        let init = init_strategy(matches);
+        let debug = matches.opt_present("d");
+        let worker_cache = cache.clone();
+        let factory = move || {
+            let emu = emu::from_elf_with_init(Path::new(&target),
+                                              init.clone())
+                .expect("Failed to create emulator from ELF");
+            let ruleset = rules::load_all(Path::new(&rules_path),
+                                          filter.clone());
+            let mut dirt = DirtEngine::new(emu, ruleset);
+            dirt.set_min_confidence(min_confidence);
+            dirt.emu_mut().set_limits(limits);
+            if let Some(ref cache) = worker_cache {
+                dirt.set_cache(cache.clone());
+            }
+            if debug {
+                dirt.debugger().attach().expect("Failed to attach debugger");
+            }
+            dirt
+        };
+        let targets = funcs.iter()
+            .map(|&(ref func, cc)| {
+                TargetInfo {
+                    fva: func.value,
+                    size: func.size,
+                    cc: cc,
+                }
+            })
+            .collect();
+
+        let identifications =
+            identify_parallel(dirt, factory, targets, jobs, |_| {
+                if show_progress {
+                    pb.inc();
+                }
+            });
+
+        if show_progress {
+            print!("\r\x1b[K");
+        }
+        for identification in identifications {
+            let func = &funcs[identification.index].0;
+            reporter.record(&function_report(func,
+                                     identification.result,
+                                     identification.emulations))
+                .expect("Failed to write report");
+        }
+    } else {
+        for &(ref func, cc) in &funcs {
+            let emu_counter = dirt.emu().emu_counter();
+            let result = dirt.identify_function(&TargetInfo {
+                fva: func.value,
+                size: func.size,
+                cc: cc,
+            });
+            let emulations = dirt.emu().emu_counter() - emu_counter;
+
+            if show_progress {
+                print!("\r\x1b[K");
+            }
+            reporter.record(&function_report(func, result, emulations))
+                .expect("Failed to write report");
+
+            if show_progress {
+                pb.inc();
+            }
+        }
+    }
+
+    reporter.finish().expect("Failed to write report");
+    if show_progress {
+        pb.finish();
+    }
+
+    if let Some(cache) = cache {
+        cache.lock()
+            .unwrap()
+            .save()
+            .unwrap_or_else(|e| fail(&format!("Failed to save cache: {}", e)));
+    }
+}
+
+/// Build the report of a function out of its identification result.
+fn function_report(func: &Symbol,
+                   result: Result<Vec<FunctionInfo>, dirt_engine::Error>,
+                   emulations: usize)
+                   -> FunctionReport {
+    let (found, error) = match result {
+        Ok(found) => (found, None),
+        Err(e) => (Vec::new(), Some(format!("{:?}", e))),
+    };
+    return FunctionReport {
+        address: func.value,
+        symbol: func.name.clone(),
+        demangled: cppfilt::unmangle(&func.name),
+        matches: found,
+        error: error,
+        emulations: emulations,
+    };
+}
+
+fn cmd_call(matches: &Matches) {
+    let (bin, mut dirt) = load_target(matches);
+    let default_cc = calling_convention(matches, &dirt);
+    let (func, cc) = single_function(matches, &*bin, default_cc);
+
+    // Every argument is either an integer or a string.
+    let argv: Vec<Rc<DataType>> = matches.free[1..]
+        .iter()
+        .map(|a| -> Rc<DataType> {
+            match parse_int(a) {
+                Some(n) => Rc::new(IntegerData(n)),
+                None => Rc::new(StringData::new(a)),
+            }
+        })
+        .collect();
+
+    if matches.opt_present("calls") {
+        dirt.emu_mut()
+            .set_call_tracing(true)
+            .expect("Failed to enable call tracing");
+    }
+
+    let mut out = output(matches);
+    let target = TargetInfo {
+        fva: func.value,
+        size: func.size,
+        cc: cc,
+    };
+    match dirt.emu_mut().call(&target, &EmuArgs::new(argv)) {
+        Ok(effects) => {
+            match effects.fault {
+                Some(ref fault) => {
+                    writeln!(out,
+                             "{}(0x{:x}): {}",
+                             func.name,
+                             func.value,
+                             fault)
+                }
+                None => {
+                    writeln!(out,
+                             "{}(0x{:x}) = 0x{:x}",
+                             func.name,
+                             func.value,
+                             effects.return_value)
                 }
             }
-            Err(e) => {
-                println!("\r\x1b[K{}: Err({:?})", func.name, e);
+            .expect("Failed to write output");
+            // Call tree, indented by depth.
+            let calls = effects.calls
+                .iter()
+                .filter(|c| c.kind == EdgeKind::Call);
+            for call in calls {
+                let name = match call.symbol {
+                    Some(ref symbol) => symbol.clone(),
+                    None => format!("sub_{:x}", call.to),
+                };
+                let indent: String =
+                    ::std::iter::repeat("  ").take(call.depth + 1).collect();
+                writeln!(out, "{}{}(0x{:x})", indent, name, call.to)
+                    .expect("Failed to write output");
             }
         }
+        Err(e) => {
+            fail(&format!("{}(0x{:x}): Err({:?})", func.name, func.value, e))
+        }
+    }
+}
+
+fn cmd_eval(matches: &Matches) {
+    let (bin, mut dirt) = load_target(matches);
+    let default_cc = calling_convention(matches, &dirt);
+    let (func, cc) = single_function(matches, &*bin, default_cc);
 
-        pb.inc();
+    let mut out = output(matches);
+    match dirt.eval_function(&TargetInfo {
+        fva: func.value,
+        size: func.size,
+        cc: cc,
+    }) {
+        Ok(mut evals) => {
+            evals.sort_by(|a, b| {
+                (&a.candidate, a.index).cmp(&(&b.candidate, b.index))
+            });
+            for eval in evals {
+                let outcome = match eval.outcome {
+                    RuleOutcome::Passed => "ok",
+                    RuleOutcome::Failed => "failed",
+                    RuleOutcome::LimitExceeded => "limit exceeded",
+                    RuleOutcome::InputTooLarge => "input too large",
+                };
+                writeln!(out, "{} #{}: {}", eval.candidate, eval.index, outcome)
+                    .expect("Failed to write output");
+            }
+        }
+        Err(e) => fail(&format!("{}: Err({:?})", func.name, e)),
     }
+}
 
-    pb.finish();
+fn cmd_list_rules(matches: &Matches) {
+    let ruleset = load_rules(matches);
+    let mut candidates: Vec<(&String, usize)> = ruleset.candidates()
+        .iter()
+        .map(|(name, rules)| (name, rules.len()))
+        .collect();
+    candidates.sort();
+
+    let mut out = output(matches);
+    for (name, count) in candidates {
+        writeln!(out, "{} ({} rules)", name, count)
+            .expect("Failed to write output");
+    }
 }
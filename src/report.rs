@@ -0,0 +1,228 @@
+use dirt_engine::FunctionInfo;
+use export;
+use export::Script;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Output format of an identification report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Human readable lines, only listing matched functions.
+    Text,
+    /// A JSON array with one object per function.
+    Json,
+    /// A CSV table with a header and one row per function.
+    Csv,
+    /// A disassembler script renaming the identified functions.
+    Script(Script),
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "idc" => Ok(Format::Script(Script::Idc)),
+            "idapython" => Ok(Format::Script(Script::IdaPython)),
+            "ghidra" => Ok(Format::Script(Script::Ghidra)),
+            "r2" => Ok(Format::Script(Script::R2)),
+            _ => Err(format!("Unknown report format: {}", s)),
+        }
+    }
+}
+
+/// FunctionReport is the outcome of the identification of a single function.
+pub struct FunctionReport {
+    pub address: u64,
+    /// Raw symbol name, as found in the binary.
+    pub symbol: String,
+    /// Demangled symbol name.
+    pub demangled: String,
+    /// Candidates matched by the function, best first.
+    pub matches: Vec<FunctionInfo>,
+    /// Error returned by the engine while identifying the function, if any.
+    pub error: Option<String>,
+    /// Number of emulated calls it took to identify the function.
+    pub emulations: usize,
+}
+
+/// Reporter writes a stream of FunctionReport in a given format.
+pub struct Reporter<W: Write> {
+    format: Format,
+    out: W,
+    count: usize,
+    /// Names given by the script formats.
+    names: export::Names,
+}
+
+impl<W: Write> Reporter<W> {
+    pub fn new(format: Format, out: W) -> Reporter<W> {
+        return Reporter {
+            format: format,
+            out: out,
+            count: 0,
+            names: export::Names::new(),
+        };
+    }
+
+    /// Write the report prologue. Must be called once before any record.
+    pub fn begin(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::Text => Ok(()),
+            Format::Json => writeln!(self.out, "["),
+            Format::Csv => {
+                writeln!(self.out,
+                         "address,symbol,demangled,matches,error,emulations")
+            }
+            Format::Script(script) => export::begin(script, &mut self.out),
+        }
+    }
+
+    /// Write a single function record.
+    pub fn record(&mut self, report: &FunctionReport) -> io::Result<()> {
+        let r = match self.format {
+            Format::Text => self.record_text(report),
+            Format::Json => self.record_json(report),
+            Format::Csv => self.record_csv(report),
+            Format::Script(script) => {
+                export::record(script, &mut self.out, &mut self.names, report)
+            }
+        };
+        self.count += 1;
+        return r;
+    }
+
+    /// Write the report epilogue and flush the output.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::Json => {
+                if self.count > 0 {
+                    try!(writeln!(self.out, ""));
+                }
+                try!(writeln!(self.out, "]"));
+            }
+            Format::Script(script) => {
+                try!(export::finish(script, &mut self.out));
+            }
+            _ => (),
+        }
+        return self.out.flush();
+    }
+
+    fn record_text(&mut self, report: &FunctionReport) -> io::Result<()> {
+        if let Some(ref e) = report.error {
+            return writeln!(self.out, "{}: Err({})", report.symbol, e);
+        }
+        if report.matches.len() > 0 {
+            return writeln!(self.out,
+                            "{}: matched by {:?}",
+                            report.demangled,
+                            report.matches
+                                .iter()
+                                .map(|m| if m.is_exact() {
+                                    m.name.clone()
+                                } else {
+                                    format!("{} ({}/{})",
+                                            m.name,
+                                            m.passed,
+                                            m.total)
+                                })
+                                .collect::<Vec<String>>());
+        }
+        return Ok(());
+    }
+
+    fn record_json(&mut self, report: &FunctionReport) -> io::Result<()> {
+        if self.count > 0 {
+            try!(writeln!(self.out, ","));
+        }
+        let matches = report.matches
+            .iter()
+            .map(|m| {
+                format!("{{\"name\":{},\"confidence\":{},\"passed\":{},\
+                         \"total\":{},\"failed\":[{}],\"limited\":[{}]}}",
+                        json_str(&m.name),
+                        m.confidence,
+                        m.passed,
+                        m.total,
+                        json_indexes(&m.failed),
+                        json_indexes(&m.limited))
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let error = match report.error {
+            Some(ref e) => json_str(e),
+            None => String::from("null"),
+        };
+        return write!(self.out,
+                      "  {{\"address\":\"0x{:x}\",\"symbol\":{},\
+                       \"demangled\":{},\"matches\":[{}],\"error\":{},\
+                       \"emulations\":{}}}",
+                      report.address,
+                      json_str(&report.symbol),
+                      json_str(&report.demangled),
+                      matches,
+                      error,
+                      report.emulations);
+    }
+
+    fn record_csv(&mut self, report: &FunctionReport) -> io::Result<()> {
+        let matches = report.matches
+            .iter()
+            .map(|m| format!("{}:{:.2}", m.name, m.confidence))
+            .collect::<Vec<String>>()
+            .join(";");
+        return writeln!(self.out,
+                        "0x{:x},{},{},{},{},{}",
+                        report.address,
+                        csv_str(&report.symbol),
+                        csv_str(&report.demangled),
+                        csv_str(&matches),
+                        csv_str(report.error
+                            .as_ref()
+                            .map(|e| e.as_str())
+                            .unwrap_or("")),
+                        report.emulations);
+    }
+}
+
+/// List of rule indexes, as the content of a JSON array.
+fn json_indexes(indexes: &[usize]) -> String {
+    return indexes.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+}
+
+/// Quote and escape a string as a JSON string literal.
+fn json_str(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+/// Quote a CSV field if it contains any special character.
+fn csv_str(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        return format!("\"{}\"", s.replace("\"", "\"\""));
+    }
+    return String::from(s);
+}
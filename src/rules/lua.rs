@@ -1,15 +1,19 @@
-use emu::args::EmuArgs;
-use emu::datatypes::{BufData, ByteData, CompositeData, DataType, IntegerData,
-                     StringData, ThisOffsetData};
+use emu::args::{EmuArgs, GuardMode, ReturnType};
+use emu::call_tracer::{CallEdge, EdgeKind};
+use emu::datatypes::{BufData, ByteData, CompositeData, DataType, DoubleData,
+                     FloatData, IntegerData, StringData, ThisOffsetData};
 use emu::emu_engine::EmuEffects;
 use lua;
-use std::env;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
 use std::path::Path;
 use std::collections::HashMap;
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
+use utils::Fnv64;
 
-pub use rules::rule::Rule;
+pub use rules::rule::{Rule, RuleOptions};
 
 const LUARULES_REG_KEY: &'static str = "dirt";
 
@@ -48,6 +52,38 @@ fn lua_effect(lua: &mut ::lua::State) -> &EmuEffects {
             .expect("EmuEffects should not be used outside of test validation");
 }
 
+fn lua_effect_return_double(lua: &mut ::lua::State) -> i32 {
+    match lua_effect(lua).return_double() {
+        Ok(value) => lua.push_number(value),
+        Err(_) => lua.push_nil(),
+    }
+    return 1;
+}
+
+fn lua_effect_return_float(lua: &mut ::lua::State) -> i32 {
+    match lua_effect(lua).return_float() {
+        Ok(value) => lua.push_number(value as f64),
+        Err(_) => lua.push_nil(),
+    }
+    return 1;
+}
+
+fn lua_effect_return_high(lua: &mut ::lua::State) -> i32 {
+    match lua_effect(lua).return_high {
+        Some(value) => lua.push_integer(value as i64),
+        None => lua.push_nil(),
+    }
+    return 1;
+}
+
+fn lua_effect_returned(lua: &mut ::lua::State) -> i32 {
+    match lua_effect(lua).returned {
+        Some(addr) => lua.push_integer(addr as i64),
+        None => lua.push_nil(),
+    }
+    return 1;
+}
+
 fn lua_effect_arg(lua: &mut ::lua::State) -> i32 {
     let n = lua.to_integer(1);
     let value = lua_effect(lua).args.nth(n as usize);
@@ -73,11 +109,205 @@ fn lua_effect_usize(lua: &mut ::lua::State) -> i32 {
     return 1;
 }
 
+fn push_bytes_table(lua: &mut ::lua::State, bytes: &[u8]) {
+    lua.new_table();
+    for (i, &b) in bytes.iter().enumerate() {
+        lua.push_integer(b as i64);
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+}
+
+fn lua_effect_writes(lua: &mut ::lua::State) -> i32 {
+    let writes = lua_effect(lua).writes.clone();
+    lua.new_table();
+    for (i, write) in writes.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(write.addr as i64);
+        lua.set_field(-2, "addr");
+        lua.push_integer(write.size() as i64);
+        lua.set_field(-2, "size");
+        lua.push_string(&write.map);
+        lua.set_field(-2, "map");
+        push_bytes_table(lua, &write.old);
+        lua.set_field(-2, "old");
+        push_bytes_table(lua, &write.new);
+        lua.set_field(-2, "new");
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_changed(lua: &mut ::lua::State) -> i32 {
+    let addr = lua.to_integer(2);
+    let size = lua.to_integer(3);
+    let changed = lua_effect(lua).changed(addr as u64, size as u64);
+    lua.push_bool(changed);
+    return 1;
+}
+
+fn lua_effect_syscalls(lua: &mut ::lua::State) -> i32 {
+    let syscalls = lua_effect(lua).syscalls.clone();
+    lua.new_table();
+    for (i, syscall) in syscalls.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(syscall.number as i64);
+        lua.set_field(-2, "number");
+        lua.new_table();
+        for (j, &arg) in syscall.args.iter().enumerate() {
+            lua.push_integer(arg as i64);
+            lua.raw_seti(-2, j as i64 + 1);
+        }
+        lua.set_field(-2, "args");
+        lua.push_string(&String::from_utf8_lossy(&syscall.data));
+        lua.set_field(-2, "data");
+        lua.push_integer(syscall.result as i64);
+        lua.set_field(-2, "result");
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_output(lua: &mut ::lua::State, fd: u64) -> i32 {
+    let output = lua_effect(lua).output(fd);
+    lua.push_string(&String::from_utf8_lossy(&output));
+    return 1;
+}
+
+fn lua_effect_stdout(lua: &mut ::lua::State) -> i32 {
+    return lua_effect_output(lua, 1);
+}
+
+fn lua_effect_stderr(lua: &mut ::lua::State) -> i32 {
+    return lua_effect_output(lua, 2);
+}
+
+fn lua_effect_allocs(lua: &mut ::lua::State) -> i32 {
+    let allocs = lua_effect(lua).heap.allocs.clone();
+    lua.new_table();
+    for (i, alloc) in allocs.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(alloc.addr as i64);
+        lua.set_field(-2, "addr");
+        lua.push_integer(alloc.size as i64);
+        lua.set_field(-2, "size");
+        lua.push_bool(alloc.freed);
+        lua.set_field(-2, "freed");
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_frees(lua: &mut ::lua::State) -> i32 {
+    let frees = lua_effect(lua).heap.frees.clone();
+    lua.new_table();
+    for (i, &addr) in frees.iter().enumerate() {
+        lua.push_integer(addr as i64);
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_heap_growth(lua: &mut ::lua::State) -> i32 {
+    let growth = lua_effect(lua).heap.growth;
+    lua.push_integer(growth as i64);
+    return 1;
+}
+
+fn lua_effect_calls(lua: &mut ::lua::State) -> i32 {
+    let calls: Vec<CallEdge> = lua_effect(lua)
+        .calls
+        .iter()
+        .filter(|c| c.kind == EdgeKind::Call)
+        .cloned()
+        .collect();
+    lua.new_table();
+    for (i, call) in calls.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(call.from as i64);
+        lua.set_field(-2, "from");
+        lua.push_integer(call.to as i64);
+        lua.set_field(-2, "addr");
+        lua.push_integer(call.depth as i64);
+        lua.set_field(-2, "depth");
+        if let Some(ref symbol) = call.symbol {
+            lua.push_string(symbol);
+            lua.set_field(-2, "name");
+        }
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_call_count(lua: &mut ::lua::State) -> i32 {
+    let name = lua.to_str(2).unwrap_or("").to_owned();
+    lua.pop(1);
+    let count = lua_effect(lua).call_count(&name);
+    lua.push_integer(count as i64);
+    return 1;
+}
+
+fn lua_effect_out_of_bounds(lua: &mut ::lua::State) -> i32 {
+    let accesses = lua_effect(lua).out_of_bounds.clone();
+    lua.new_table();
+    for (i, access) in accesses.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(access.arg as i64);
+        lua.set_field(-2, "arg");
+        lua.push_integer(access.offset);
+        lua.set_field(-2, "offset");
+        lua.push_integer(access.size as i64);
+        lua.set_field(-2, "size");
+        lua.push_bool(access.write);
+        lua.set_field(-2, "write");
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_uninit_reads(lua: &mut ::lua::State) -> i32 {
+    let reads = lua_effect(lua).uninit_reads.clone();
+    lua.new_table();
+    for (i, read) in reads.iter().enumerate() {
+        lua.new_table();
+        lua.push_integer(read.addr as i64);
+        lua.set_field(-2, "addr");
+        lua.push_integer(read.size as i64);
+        lua.set_field(-2, "size");
+        lua.push_string(&read.map);
+        lua.set_field(-2, "map");
+        lua.raw_seti(-2, i as i64 + 1);
+    }
+    return 1;
+}
+
+fn lua_effect_fault(lua: &mut ::lua::State) -> i32 {
+    let fault = match lua_effect(lua).fault {
+        Some(ref fault) => fault.clone(),
+        None => {
+            lua.push_nil();
+            return 1;
+        }
+    };
+    lua.new_table();
+    lua.push_string(fault.kind());
+    lua.set_field(-2, "kind");
+    if let Some(addr) = fault.addr() {
+        lua.push_integer(addr as i64);
+        lua.set_field(-2, "addr");
+    }
+    if let Some(map) = fault.map() {
+        lua.push_string(map);
+        lua.set_field(-2, "map");
+    }
+    return 1;
+}
+
 pub struct LuaRule {
     pub lua: Weak<RefCell<::lua::State>>,
     pub fn_ref: ::lua::Reference,
     pub name: String,
     pub args: EmuArgs,
+    pub options: RuleOptions,
 }
 
 impl Rule for LuaRule {
@@ -89,6 +319,10 @@ impl Rule for LuaRule {
         return &self.args;
     }
 
+    fn options(&self) -> &RuleOptions {
+        return &self.options;
+    }
+
     fn verify(&self, effects: &EmuEffects) -> bool {
         let lua_ref = self.lua.upgrade().unwrap();
         let mut lua = lua_ref.borrow_mut();
@@ -119,6 +353,10 @@ impl Rule for LuaRule {
 pub struct LuaRules {
     lua: Rc<RefCell<::lua::State>>,
     candidates_rules: HashMap<String, Vec<LuaRule>>,
+    /// Only keep the rules of this candidate, if set.
+    filter: Option<String>,
+    /// Hash of the loaded rules files and filter.
+    version: Fnv64,
 }
 
 fn lua_rule(lua: &mut ::lua::State) -> i32 {
@@ -196,6 +434,55 @@ fn lua_byte(lua: &mut ::lua::State) -> i32 {
     return 1;
 }
 
+struct LuaFloatData(f32);
+
+fn lua_float(lua: &mut ::lua::State) -> i32 {
+    let value = lua.to_number(1);
+    let buf: *mut LuaFloatData = lua.new_userdata_typed();
+    if buf.is_null() {
+        panic!("Lua error");
+    }
+    lua.set_metatable_from_registry("FloatData");
+
+    unsafe { ::std::ptr::write(buf, LuaFloatData(value as f32)) };
+    return 1;
+}
+
+struct LuaReturnsData(ReturnType);
+
+fn lua_returns(lua: &mut ::lua::State) -> i32 {
+    let ret = ReturnType::Struct(lua.to_integer(1) as u64);
+    let buf: *mut LuaReturnsData = lua.new_userdata_typed();
+    if buf.is_null() {
+        panic!("Lua error");
+    }
+    lua.set_metatable_from_registry("ReturnsData");
+
+    unsafe { ::std::ptr::write(buf, LuaReturnsData(ret)) };
+    return 1;
+}
+
+fn lua_returns_pair(lua: &mut ::lua::State) -> i32 {
+    let buf: *mut LuaReturnsData = lua.new_userdata_typed();
+    if buf.is_null() {
+        panic!("Lua error");
+    }
+    lua.set_metatable_from_registry("ReturnsData");
+
+    unsafe { ::std::ptr::write(buf, LuaReturnsData(ReturnType::Pair)) };
+    return 1;
+}
+
+/// Raise a Lua error with |msg|, reported by `load`. The error doesn't
+/// unwind the Rust frames, so the caller must not hold any owned value.
+fn raise_error(lua: &mut ::lua::State, msg: String) -> ! {
+    lua.location(1);
+    lua.push_string(&msg);
+    lua.concat(2);
+    drop(msg);
+    lua.error();
+}
+
 fn pop_error(lua: &mut ::lua::State) -> Error {
     let err = Error::LuaError(lua.to_str(-1).unwrap().to_owned());
     lua.pop(1);
@@ -208,6 +495,8 @@ impl LuaRules {
         let mut lua_rules = Box::new(LuaRules {
             lua: Rc::new(RefCell::new(lua::State::new())),
             candidates_rules: HashMap::new(),
+            filter: None,
+            version: Fnv64::new(),
         });
 
         // Interface all the helpers functions.
@@ -216,15 +505,42 @@ impl LuaRules {
             let dirt_fns = &[("rule", lua_func!(lua_rule)),
                              ("Buf", lua_func!(lua_buf)),
                              ("Byte", lua_func!(lua_byte)),
+                             ("Float", lua_func!(lua_float)),
+                             ("Returns", lua_func!(lua_returns)),
+                             ("ReturnsPair", lua_func!(lua_returns_pair)),
                              ("This", lua_func!(lua_this))];
             lua.new_lib(dirt_fns);
             lua.set_global("Dirt");
 
             let effects_fns = &[("return_value",
                                  lua_func!(lua_effect_return_value)),
+                                ("return_double",
+                                 lua_func!(lua_effect_return_double)),
+                                ("return_float",
+                                 lua_func!(lua_effect_return_float)),
+                                ("return_high",
+                                 lua_func!(lua_effect_return_high)),
+                                ("returned", lua_func!(lua_effect_returned)),
                                 ("arg", lua_func!(lua_effect_arg)),
                                 ("str", lua_func!(lua_effect_str)),
-                                ("usize", lua_func!(lua_effect_usize))];
+                                ("usize", lua_func!(lua_effect_usize)),
+                                ("writes", lua_func!(lua_effect_writes)),
+                                ("changed", lua_func!(lua_effect_changed)),
+                                ("syscalls", lua_func!(lua_effect_syscalls)),
+                                ("stdout", lua_func!(lua_effect_stdout)),
+                                ("stderr", lua_func!(lua_effect_stderr)),
+                                ("allocs", lua_func!(lua_effect_allocs)),
+                                ("frees", lua_func!(lua_effect_frees)),
+                                ("heap_growth",
+                                 lua_func!(lua_effect_heap_growth)),
+                                ("calls", lua_func!(lua_effect_calls)),
+                                ("call_count",
+                                 lua_func!(lua_effect_call_count)),
+                                ("out_of_bounds",
+                                 lua_func!(lua_effect_out_of_bounds)),
+                                ("uninit_reads",
+                                 lua_func!(lua_effect_uninit_reads)),
+                                ("fault", lua_func!(lua_effect_fault))];
             lua.new_metatable("EmuEffects");
             lua.new_lib_table(effects_fns);
             lua.set_fns(effects_fns, 0);
@@ -236,6 +552,8 @@ impl LuaRules {
 
             lua.new_metatable("ThisData");
             lua.new_metatable("ByteData");
+            lua.new_metatable("FloatData");
+            lua.new_metatable("ReturnsData");
 
             lua.load_library(::lua::Library::Base);
             lua.load_library(::lua::Library::Io);
@@ -256,6 +574,17 @@ impl LuaRules {
     }
 
     pub fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let mut source = Vec::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut source))
+            .map_err(|e| {
+                Error::LuaError(format!("{}: {}", path.display(), e))
+            }));
+        self.version.write(&source);
+        if let Some(ref filter) = self.filter {
+            self.version.write(filter.as_bytes());
+        }
+
         let mut lua = self.lua.borrow_mut();
         let r = lua.load_file(path.to_str().unwrap());
         if r.is_err() {
@@ -282,34 +611,139 @@ impl LuaRules {
         &self.candidates_rules
     }
 
+    /// Returns a hash identifying the loaded rules. Rules loaded from the
+    /// same files, in the same order, have the same version.
+    pub fn version(&self) -> u64 {
+        return self.version.finish();
+    }
+
+    /// Ignore every rules not belonging to the candidate |filter| in the
+    /// subsequently loaded files.
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter;
+    }
+
     fn on_rule(&mut self, lua: &mut ::lua::State) -> i32 {
+        return match self.parse_rule(lua) {
+            Ok(()) => 0,
+            Err(msg) => raise_error(lua, msg),
+        };
+    }
+
+    fn parse_rule(&mut self, lua: &mut ::lua::State) -> Result<(), String> {
         let name = lua.to_str(1)
             .unwrap()
             .to_owned();
-        if let Ok(filter) = env::var("FILTER") {
-            if filter != name {
-                return 0;
+        if let Some(ref filter) = self.filter {
+            if filter != &name {
+                return Ok(());
             }
         }
 
         lua.pop(1);
-        let top = lua.get_top();
+        // An options table can follow the rule's function.
+        let mut top = lua.get_top();
+        let (options, guard) = if lua.is_table(top) && lua.is_fn(top - 1) {
+            let options = try!(self.parse_rule_options(lua, top));
+            let guard = try!(self.parse_guard_mode(lua, top));
+            lua.pop(1);
+            top -= 1;
+            (options, guard)
+        } else {
+            (RuleOptions::default(), GuardMode::Off)
+        };
+
+        // The return type is declared among the arguments, but isn't one.
         let mut args: Vec<Rc<DataType>> = Vec::new();
+        let mut ret = ReturnType::Scalar;
         for arg_n in 2..top {
+            if let Some(&mut LuaReturnsData(returns)) = unsafe {
+                lua.test_userdata_typed(arg_n, "ReturnsData")
+            } {
+                ret = returns;
+                continue;
+            }
             args.push(self.parse_rule_argument(lua, arg_n));
         }
         let fn_ref = lua.reference(lua::REGISTRYINDEX);
+        let mut args = EmuArgs::new(args);
+        args.set_return_type(ret);
+        args.set_guard_mode(guard);
 
         let rule: LuaRule = LuaRule {
             lua: Rc::downgrade(&self.lua),
             fn_ref: fn_ref,
             name: name,
-            args: EmuArgs::new(args),
+            args: args,
+            options: options,
         };
 
         self.add_rule(rule);
 
-        return 0;
+        return Ok(());
+    }
+
+    fn parse_rule_options(&mut self,
+                          lua: &mut ::lua::State,
+                          table_n: i32)
+                          -> Result<RuleOptions, String> {
+        let mut options = RuleOptions::default();
+        lua.get_field(table_n, "weight");
+        if lua.is_number(-1) {
+            options.weight = lua.to_number(-1);
+        }
+        lua.pop(1);
+        // The confidence is the ratio of the passed weights, it is only
+        // meaningful with finite positive weights.
+        if !options.weight.is_finite() || options.weight < 0.0 {
+            return Err(format!("Invalid rule weight: {}", options.weight));
+        }
+        lua.get_field(table_n, "trace_calls");
+        options.trace_calls = lua.to_bool(-1);
+        lua.pop(1);
+        // The timeout is given in milliseconds.
+        lua.get_field(table_n, "timeout");
+        if lua.is_number(-1) {
+            options.timeout = Some((lua.to_number(-1) * 1000.0) as u64);
+        }
+        lua.pop(1);
+        lua.get_field(table_n, "max_insns");
+        if lua.is_integer(-1) {
+            options.max_count = Some(lua.to_integer(-1) as usize);
+        }
+        lua.pop(1);
+        lua.get_field(table_n, "expect_fault");
+        options.expect_fault = lua.to_bool(-1);
+        lua.pop(1);
+        return Ok(options);
+    }
+
+    /// Parse the `guard_pages` option: "overflow", or true, puts the
+    /// arguments' end against a guard page and "underflow" their start.
+    fn parse_guard_mode(&mut self,
+                        lua: &mut ::lua::State,
+                        table_n: i32)
+                        -> Result<GuardMode, String> {
+        lua.get_field(table_n, "guard_pages");
+        let guard = if lua.is_string(-1) {
+            // to_str pushes the converted copy.
+            let mode = lua.to_str(-1).unwrap().to_owned();
+            lua.pop(1);
+            match mode.as_str() {
+                "overflow" => GuardMode::Overflow,
+                "underflow" => GuardMode::Underflow,
+                _ => {
+                    lua.pop(1);
+                    return Err(format!("Unsupported guard_pages: {}", mode));
+                }
+            }
+        } else if lua.to_bool(-1) {
+            GuardMode::Overflow
+        } else {
+            GuardMode::Off
+        };
+        lua.pop(1);
+        return Ok(guard);
     }
 
     fn parse_rule_argument(&mut self,
@@ -320,6 +754,11 @@ impl LuaRules {
             let arg = lua.to_integer(arg_n) as u64;
             return Rc::new(IntegerData(arg));
         }
+        if lua.type_of(arg_n) == Some(::lua::Type::Number) {
+            // Numbers of the float subtype, such as 2.0, are doubles. Numeric
+            // strings are left to the string case.
+            return Rc::new(DoubleData(lua.to_number(arg_n)));
+        }
         if lua.is_string(arg_n) {
             let arg = lua.to_str(arg_n).unwrap().to_owned();
             lua.pop(1);
@@ -357,6 +796,13 @@ impl LuaRules {
                 return Rc::new(ByteData(byte));
             }
         }
+        {
+            if let Some(&mut LuaFloatData(value)) = unsafe {
+                lua.test_userdata_typed(arg_n, "FloatData")
+            } {
+                return Rc::new(FloatData(value));
+            }
+        }
 
         panic!("Unsupported type: {}", lua.typename_at(arg_n));
     }
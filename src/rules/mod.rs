@@ -1,23 +1,27 @@
 pub mod rule;
 pub mod lua;
 
-pub use self::rule::Rule;
+pub use self::rule::{Rule, RuleOptions};
 pub use self::lua::LuaRules as RuleSet;
 
 use std::fs;
 use std::path::Path;
 
-pub fn load_all(path: &Path) -> Box<RuleSet> {
+pub fn load_all(path: &Path, filter: Option<String>) -> Box<RuleSet> {
     let mut lua = RuleSet::new();
+    lua.set_filter(filter);
 
     // List lua rules files in rules folder.
     let paths = fs::read_dir(path).unwrap();
 
-    // Load and parse each rules.
-    let filepaths =
-        paths.filter(|p| p.as_ref().unwrap().file_type().unwrap().is_file());
-    for dir_entry in filepaths {
-        let entry_path = dir_entry.unwrap().path();
+    // Load and parse each rules, in a stable order so the ruleset version
+    // only depends on the files content.
+    let mut filepaths: Vec<_> = paths.map(|p| p.unwrap())
+        .filter(|p| p.file_type().unwrap().is_file())
+        .map(|p| p.path())
+        .collect();
+    filepaths.sort();
+    for entry_path in filepaths {
         let path = entry_path.as_path();
         lua.load(path).expect("Failed to load rules");
     }
@@ -1,8 +1,50 @@
+use emu::EmuLimits;
 use emu::emu_engine::EmuEffects;
 use emu::args::EmuArgs;
 
+/// RuleOptions are the optional settings of a rule.
+#[derive(Clone, Debug)]
+pub struct RuleOptions {
+    /// Weight of the rule in its candidate's confidence score.
+    pub weight: f64,
+    /// Record the calls made by the function, for rules checking them.
+    pub trace_calls: bool,
+    /// Emulation timeout, in microseconds, instead of the engine's.
+    pub timeout: Option<u64>,
+    /// Maximum number of emulated instructions, instead of the engine's.
+    pub max_count: Option<usize>,
+    /// The function is expected to fault rather than return. Faults on the
+    /// emulation limits never pass.
+    pub expect_fault: bool,
+}
+
+impl RuleOptions {
+    /// Emulation limits of the rule, with |defaults| for the ones it doesn't
+    /// set.
+    pub fn limits(&self, defaults: EmuLimits) -> EmuLimits {
+        return EmuLimits {
+            timeout: self.timeout.unwrap_or(defaults.timeout),
+            max_count: self.max_count.unwrap_or(defaults.max_count),
+            max_data: defaults.max_data,
+        };
+    }
+}
+
+impl Default for RuleOptions {
+    fn default() -> RuleOptions {
+        return RuleOptions {
+            weight: 1.0,
+            trace_calls: false,
+            timeout: None,
+            max_count: None,
+            expect_fault: false,
+        };
+    }
+}
+
 pub trait Rule {
     fn name<'a>(&'a self) -> &'a str;
     fn args<'a>(&'a self) -> &'a EmuArgs;
+    fn options<'a>(&'a self) -> &'a RuleOptions;
     fn verify(&self, result: &EmuEffects) -> bool;
 }
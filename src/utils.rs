@@ -1,3 +1,4 @@
+use std::hash::Hasher;
 use std::io;
 use std::io::Write;
 
@@ -18,3 +19,26 @@ impl<T, E> LogError<E> for Result<T, E> {
         return self;
     }
 }
+
+/// 64 bits FNV-1a hasher. Unlike the std hashers, its output is stable across
+/// runs and Rust versions, so it can be used to key persistent data.
+pub struct Fnv64(u64);
+
+impl Fnv64 {
+    pub fn new() -> Fnv64 {
+        return Fnv64(0xcbf29ce484222325);
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        return self.0;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
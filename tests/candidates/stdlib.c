@@ -1,5 +1,7 @@
+#include <math.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
 
 #define CANDIDATE(SYM) \
   const char test_##SYM[]=#SYM; \
@@ -8,6 +10,13 @@
     f(); \
   }
 
+/* Test through a wrapper the functions whose symbol is an IFUNC resolver. */
+#define CANDIDATE_FN(SYM, RET, PARAMS, ARGS) \
+  const char test_##SYM##_expect[]=#SYM; \
+  RET test_##SYM##_fn PARAMS { \
+    return SYM ARGS; \
+  }
+
 int atoi(const char* str) {
   char* p = str;
   int n = 0;
@@ -25,6 +34,12 @@ int atoi(const char* str) {
 
 CANDIDATE(sprintf);
 CANDIDATE(atoi);
+CANDIDATE(puts);
+CANDIDATE(strdup);
+CANDIDATE(atof);
+CANDIDATE(sqrt);
+CANDIDATE_FN(strlen, size_t, (const char* s), (s));
+CANDIDATE_FN(memset, void*, (void* s, int c, size_t n), (s, c, n));
 
 int main(){
   return 0;
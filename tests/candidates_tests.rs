@@ -48,7 +48,8 @@ fn tests_all_candidates() {
         let emu = emu::from_elf(path)
             .expect("Failed to create emulator from ELF");
         // Load the ruleset.
-        let ruleset = rules::load_all(Path::new("./rules"));
+        let ruleset = rules::load_all(Path::new("./rules"),
+                                      env::var("FILTER").ok());
         // Create the DIRT engine.
         let mut dirt = DirtEngine::new(emu, ruleset);
 
@@ -64,7 +65,11 @@ fn tests_all_candidates() {
         let results: Vec<bool> = tests_iter.iter()
             .map(|&Candidate(ref fn_name, fva)| {
                 let cc = dirt.default_cc();
-                match dirt.identify_function(&TargetInfo { fva: fva, cc: cc }) {
+                match dirt.identify_function(&TargetInfo {
+                    fva: fva,
+                    size: 0,
+                    cc: cc,
+                }) {
                     Ok(matches) => {
                         if matches.len() == 0 {
                             println!("{}: No match", fn_name);